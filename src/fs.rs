@@ -24,11 +24,15 @@ pub use crate::fs_file::get_created;
 pub use crate::fs_file::get_file_size;
 pub use crate::fs_file::get_modified;
 pub use crate::fs_file::get_modified_time_f64;
+pub use crate::fs_file::is_binary;
+pub use crate::fs_file::is_binary_bytes;
+pub use crate::fs_file::read_text_with_encoding;
 pub use crate::fs_file::read_to_bytes;
 pub use crate::fs_file::read_to_string;
 pub use crate::fs_file::set_modified_from_iso8601;
 pub use crate::fs_file::write_base64_str;
 pub use crate::fs_file::write_bytes;
+pub use crate::fs_file::write_bytes_atomic;
 pub use crate::fs_file::write_str;
 pub use crate::fs_file::FileInfo;
 
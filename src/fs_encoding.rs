@@ -0,0 +1,190 @@
+use encoding_rs::{
+    DecoderResult, EncoderResult, Encoding, GBK, SHIFT_JIS, UTF_16BE, UTF_16LE, UTF_8,
+};
+use std::io::{self, ErrorKind};
+
+// Bytes looked at to guess the encoding of a text
+const SAMPLE_SIZE: usize = 64 * 1024;
+
+// Guess the encoding of a text: a BOM first, then UTF-16 without BOM from the position of its
+// NUL bytes, valid UTF-8, and last the legacy CJK encodings GBK and Shift-JIS. Text that
+// is none of those is read as UTF-8 with replacement characters.
+pub fn detect_encoding(bytes: &[u8]) -> &'static Encoding {
+    if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+        return encoding;
+    }
+
+    let sample = &bytes[..bytes.len().min(SAMPLE_SIZE)];
+    let truncated = sample.len() < bytes.len();
+    // NUL bytes are valid UTF-8, so UTF-16 is checked first
+    if let Some(encoding) = detect_utf16(sample) {
+        return encoding;
+    }
+    match std::str::from_utf8(sample) {
+        Ok(_) => return UTF_8,
+        // A multibyte char cut by the end of the sample
+        Err(e) if truncated && e.error_len().is_none() => return UTF_8,
+        Err(_) => {}
+    }
+
+    let gbk = decode_sample(GBK, sample, truncated);
+    let sjis = decode_sample(SHIFT_JIS, sample, truncated);
+    match (gbk, sjis) {
+        // Japanese text often also decodes as GBK, but only Shift-JIS gives kana
+        (_, Some(s)) if s.chars().any(is_kana) => SHIFT_JIS,
+        (Some(_), _) => GBK,
+        (None, Some(_)) => SHIFT_JIS,
+        (None, None) => UTF_8,
+    }
+}
+
+// UTF-16 text without BOM: mostly ASCII or Latin text has NUL high bytes, at the odd
+// positions for little endian and at the even ones for big endian
+pub(crate) fn detect_utf16(sample: &[u8]) -> Option<&'static Encoding> {
+    let pairs = sample.len() / 2;
+    if pairs < 2 {
+        return None;
+    }
+
+    let even = sample.iter().step_by(2).filter(|b| **b == 0).count();
+    let odd = sample
+        .iter()
+        .skip(1)
+        .step_by(2)
+        .filter(|b| **b == 0)
+        .count();
+    if odd * 10 >= pairs * 3 && even * 20 <= pairs {
+        return Some(UTF_16LE);
+    }
+    if even * 10 >= pairs * 3 && odd * 20 <= pairs {
+        return Some(UTF_16BE);
+    }
+
+    None
+}
+
+// The sample decoded without replacement, None if it is malformed in this encoding
+fn decode_sample(encoding: &'static Encoding, sample: &[u8], truncated: bool) -> Option<String> {
+    let mut decoder = encoding.new_decoder_without_bom_handling();
+    let mut res =
+        String::with_capacity(decoder.max_utf8_buffer_length_without_replacement(sample.len())?);
+    match decoder.decode_to_string_without_replacement(sample, &mut res, !truncated) {
+        (DecoderResult::InputEmpty, _) => Some(res),
+        _ => None,
+    }
+}
+
+fn is_kana(c: char) -> bool {
+    ('\u{3040}'..='\u{30ff}').contains(&c) || ('\u{ff66}'..='\u{ff9f}').contains(&c)
+}
+
+// Transcode a text to UTF-8 with the detected encoding, the BOM is removed. Returns the text
+// and the name of the encoding.
+pub fn decode_text(bytes: &[u8]) -> (String, &'static str) {
+    let encoding = detect_encoding(bytes);
+    let (text, encoding, _) = encoding.decode(bytes);
+    (text.into_owned(), encoding.name())
+}
+
+// Like `decode_text` but fails instead of replacing the bytes that are malformed in the
+// detected encoding, for a text that is written back
+pub fn decode_text_strict(bytes: &[u8]) -> Result<(String, &'static str), io::Error> {
+    let encoding = detect_encoding(bytes);
+    let (text, encoding, had_errors) = encoding.decode(bytes);
+    if had_errors {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("malformed {} text", encoding.name()),
+        ));
+    }
+    Ok((text.into_owned(), encoding.name()))
+}
+
+// Whether the text starts with a UTF-8 or UTF-16 BOM, which `decode_text` removes
+pub fn has_bom(bytes: &[u8]) -> bool {
+    Encoding::for_bom(bytes).is_some()
+}
+
+// Encode a UTF-8 text back to the encoding it was read from, `encoding` is a name returned
+// by `decode_text`, with a BOM first if `with_bom` and the encoding has one. Fails on the
+// characters the encoding can not represent. UTF-16 has no encoder in `encoding_rs` and is
+// written by hand.
+pub fn encode_text(text: &str, encoding: &str, with_bom: bool) -> Result<Vec<u8>, io::Error> {
+    let encoding = match Encoding::for_label(encoding.as_bytes()) {
+        Some(e) => e,
+        None => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("unknown encoding: {}", encoding),
+            ))
+        }
+    };
+    let bom: &[u8] = match encoding {
+        _ if !with_bom => &[],
+        e if e == UTF_8 => &[0xEF, 0xBB, 0xBF],
+        e if e == UTF_16LE => &[0xFF, 0xFE],
+        e if e == UTF_16BE => &[0xFE, 0xFF],
+        _ => &[],
+    };
+    let mut res: Vec<u8> = bom.to_vec();
+
+    if encoding == UTF_16LE {
+        res.extend(text.encode_utf16().flat_map(|u| u.to_le_bytes()));
+        return Ok(res);
+    }
+    if encoding == UTF_16BE {
+        res.extend(text.encode_utf16().flat_map(|u| u.to_be_bytes()));
+        return Ok(res);
+    }
+
+    let mut encoder = encoding.new_encoder();
+    let len = encoder
+        .max_buffer_length_from_utf8_without_replacement(text.len())
+        .unwrap_or(text.len() * 4);
+    res.reserve(len);
+    let (result, _) = encoder.encode_from_utf8_to_vec_without_replacement(text, &mut res, true);
+    match result {
+        EncoderResult::InputEmpty => Ok(res),
+        EncoderResult::Unmappable(c) => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("{:?} can not be encoded in {}", c, encoding.name()),
+        )),
+        EncoderResult::OutputFull => Err(io::Error::other("encode buffer full")),
+    }
+}
+
+#[test]
+fn test_detect_encoding() {
+    let text = |bytes: &[u8]| decode_text(bytes);
+
+    assert_eq!(text("héllo".as_bytes()), ("héllo".to_string(), "UTF-8"));
+    assert_eq!(text(&[0xEF, 0xBB, 0xBF, b'a']), ("a".to_string(), "UTF-8"));
+    assert_eq!(
+        text(&[0xFF, 0xFE, b'a', 0, b'b', 0]),
+        ("ab".to_string(), "UTF-16LE")
+    );
+    assert_eq!(
+        text(&[b'a', 0, b'b', 0, b'c', 0, 0x2D, 0x4E]),
+        ("abc中".to_string(), "UTF-16LE")
+    );
+    assert_eq!(
+        text(&[0, b'a', 0, b'b', 0, b'c']),
+        ("abc".to_string(), "UTF-16BE")
+    );
+
+    let gbk = encode_text("中文搜索 search", "gbk", false).unwrap();
+    assert_eq!(text(&gbk), ("中文搜索 search".to_string(), "GBK"));
+    let sjis = encode_text("日本語のテキスト", "shift_jis", false).unwrap();
+    assert_eq!(text(&sjis), ("日本語のテキスト".to_string(), "Shift_JIS"));
+    assert!(encode_text("中文 😀", "gbk", false).is_err());
+    assert!(encode_text("a", "nothing", false).is_err());
+
+    let utf16 = encode_text("ab", "UTF-16LE", true).unwrap();
+    assert_eq!(utf16, [0xFF, 0xFE, b'a', 0, b'b', 0]);
+    assert!(has_bom(&utf16));
+    assert_eq!(encode_text("ab", "gbk", true).unwrap(), b"ab");
+
+    assert_eq!(text(&[0xFF, b'a']).1, "UTF-8");
+    assert!(decode_text_strict(b"caf\xe9 rust").is_err());
+    assert_eq!(decode_text_strict(&gbk).unwrap(), text(&gbk));
+}
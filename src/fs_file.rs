@@ -2,6 +2,7 @@ use chrono::{DateTime, FixedOffset, TimeZone, Utc};
 use filetime::{set_file_mtime, FileTime};
 use std::error::Error;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::{
     fs::{self, DirEntry, File},
     io::{self, Read, Write},
@@ -9,7 +10,7 @@ use std::{
     time::SystemTime,
 };
 
-use crate::{datetime::get_timezone_offset_of_utc, fs as x_fs};
+use crate::{datetime::get_timezone_offset_of_utc, fs as x_fs, fs_encoding};
 
 use base64::engine::{general_purpose::STANDARD as b64_STANDARD, Engine};
 
@@ -55,11 +56,17 @@ pub fn read_to_string(file_path: &str) -> Result<String, Box<dyn Error>> {
         return Ok("".to_string());
     };
 
-    let mut file = File::open(&file_path)?;
-    let mut s = "".to_string();
-    let _ = file.read_to_string(&mut s)?;
+    Ok(read_text_with_encoding(file_path)?.0)
+}
+
+// Read a text file in any encoding found by `fs_encoding::detect_encoding` (UTF-8, UTF-16,
+// GBK, Shift-JIS) as UTF-8, also returns the name of the encoding
+pub fn read_text_with_encoding(file_path: &str) -> Result<(String, &'static str), Box<dyn Error>> {
+    let mut file = File::open(file_path)?;
+    let mut buf: Vec<u8> = Vec::new();
+    file.read_to_end(&mut buf)?;
 
-    Ok(s)
+    Ok(fs_encoding::decode_text(&buf))
 }
 
 pub fn read_to_bytes(file_path: &str, log_open_err: bool) -> Result<Vec<u8>, Box<dyn Error>> {
@@ -74,6 +81,33 @@ pub fn read_to_bytes(file_path: &str, log_open_err: bool) -> Result<Vec<u8>, Box
     Ok(buf)
 }
 
+// Guess if a file is binary like git does: a NUL byte in the first 8000 bytes.
+// UTF-16 text files also contain NUL bytes, so they are not binary if they start with a BOM or
+// look like UTF-16.
+pub fn is_binary(file_path: &str) -> bool {
+    let file = match File::open(file_path) {
+        Ok(f) => f,
+        Err(_) => return false,
+    };
+
+    let mut buf: Vec<u8> = Vec::with_capacity(8000);
+    if file.take(8000).read_to_end(&mut buf).is_err() {
+        return false;
+    }
+
+    is_binary_bytes(&buf)
+}
+
+// Same as `is_binary` for content already in memory
+pub fn is_binary_bytes(bytes: &[u8]) -> bool {
+    if bytes.starts_with(&[0xFF, 0xFE]) || bytes.starts_with(&[0xFE, 0xFF]) {
+        return false;
+    }
+
+    let sample = &bytes[..bytes.len().min(8000)];
+    sample.contains(&0) && fs_encoding::detect_utf16(sample).is_none()
+}
+
 pub fn write_str(file_path: &str, file_content: &str) -> Result<(), Box<dyn Error>> {
     let _ = crate::fs::check_or_create_dir(x_fs::get_parent_dir_path(file_path).as_str());
 
@@ -92,6 +126,62 @@ pub fn write_bytes(file_path: &str, file_content: &Vec<u8>) -> Result<(), Box<dy
     Ok(())
 }
 
+static TMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// Create a new temporary file in the directory of `file_path`, with a random suffix so that
+// no existing file is overwritten
+fn create_tmp_file(file_path: &str) -> Result<(String, File), io::Error> {
+    let path = Path::new(file_path);
+    let name = path
+        .file_name()
+        .map_or(String::new(), |n| n.to_string_lossy().to_string());
+    loop {
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        let suffix = nanos
+            ^ ((std::process::id() as u64) << 32)
+            ^ TMP_FILE_COUNTER.fetch_add(1, Ordering::SeqCst);
+        let tmp_path =
+            x_fs::path_buf_to_string(path.with_file_name(format!(".{}.{:x}.tmp", name, suffix)));
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)
+        {
+            Ok(file) => return Ok((tmp_path, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Write to a temporary file next to `file_path` then rename it, so readers never see a
+// partially written file. The permissions of an existing file are kept.
+pub fn write_bytes_atomic(file_path: &str, file_content: &[u8]) -> Result<(), Box<dyn Error>> {
+    let _ = crate::fs::check_or_create_dir(x_fs::get_parent_dir_path(file_path).as_str());
+
+    let permissions = fs::metadata(file_path).ok().map(|m| m.permissions());
+    let (tmp_path, mut file) = create_tmp_file(file_path)?;
+    let res = file
+        .write_all(file_content)
+        .and_then(|_| match permissions {
+            Some(p) => file.set_permissions(p),
+            None => Ok(()),
+        })
+        .and_then(|_| file.sync_all())
+        .and_then(|_| {
+            drop(file);
+            fs::rename(&tmp_path, file_path)
+        });
+    if let Err(e) = res {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(Box::new(e));
+    }
+
+    Ok(())
+}
+
 pub fn write_base64_str(file_path: &str, file_content_base64: &str) -> Result<(), Box<dyn Error>> {
     let file_content_string: String;
 
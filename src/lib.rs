@@ -6,14 +6,36 @@ pub mod constants;
 pub mod datetime;
 pub mod fs;
 pub mod fs_dir;
+pub mod fs_encoding;
 pub mod fs_file;
 pub mod hash;
 pub mod json_toml;
 pub mod logger;
 pub mod progress;
 pub mod search;
+pub mod search_archive;
+pub mod search_chunked;
+pub mod search_diff;
+pub mod search_extract;
+pub mod search_filter;
+pub mod search_fuzzy;
+pub mod search_html;
+pub mod search_index;
+pub mod search_markdown;
+pub mod search_match;
+pub mod search_normalize;
+pub mod search_parallel;
+pub mod search_query;
+pub mod search_rank;
+pub mod search_replace;
+pub mod search_saved;
+pub mod search_stream;
+pub mod search_tokenizer;
 pub mod sys;
 pub mod web;
+pub mod web_client;
+pub mod web_download;
+pub mod web_upload;
 pub mod zip;
 
 #[cfg(test)]
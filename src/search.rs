@@ -1,66 +1,107 @@
-use html_escape::decode_html_entities;
 use log::debug;
-use log::error;
 use regex::Regex;
 use serde::Deserialize;
 use serde::Serialize;
 use std::cmp::max;
 use std::cmp::min;
-use std::fs;
 use std::fs::File;
 use std::io;
-use std::io::BufReader;
 use std::io::ErrorKind;
 use std::io::Read;
+use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use crate::fs::path_buf_to_string;
-
-#[derive(Serialize, Deserialize, Debug)]
+use crate::fs_file::{file_info, FileInfo};
+use crate::search_archive::{is_archive, search_archive};
+use crate::search_chunked::{is_too_big, search_file_chunked};
+use crate::search_filter::DirWalker;
+use crate::search_fuzzy::find_fuzzy_spans;
+use crate::search_match::{build_line_contexts, build_matches, render_matches};
+use crate::search_normalize::find_plain_spans;
+use crate::search_rank::{rank_results, CorpusStats};
+use crate::search_tokenizer::find_word_spans;
+
+pub use crate::search_archive::split_archive_path;
+pub use crate::search_diff::diff_results;
+pub use crate::search_diff::SearchResultDiff;
+pub use crate::search_diff::SearchWatch;
+pub use crate::search_extract::sniff_mime;
+pub use crate::search_extract::Extractor;
+pub use crate::search_extract::ExtractorRegistry;
+pub use crate::search_extract::HtmlExtractor;
+pub use crate::search_extract::JsonTextExtractor;
+pub use crate::search_extract::MarkdownExtractor;
+pub use crate::search_extract::PlainTextExtractor;
+pub use crate::search_filter::FileFilter;
+pub use crate::search_fuzzy::find_matches_fuzzy;
+pub use crate::search_html::extract_html_text;
+pub use crate::search_html::ExtractedText;
+pub use crate::search_html::HtmlTextOptions;
+pub use crate::search_html::TextField;
+pub use crate::search_index::search_in_dir_indexed;
+pub use crate::search_index::search_in_dir_indexed_with_options;
+pub use crate::search_index::SearchIndex;
+pub use crate::search_markdown::extract_markdown;
+pub use crate::search_markdown::MarkdownOptions;
+pub use crate::search_match::LineContext;
+pub use crate::search_match::MatchLine;
+pub use crate::search_match::SearchMatch;
+pub use crate::search_normalize::find_matches_plain;
+pub use crate::search_normalize::Normalization;
+pub use crate::search_normalize::PlainMatchOptions;
+pub use crate::search_parallel::search_in_dir_parallel;
+pub use crate::search_parallel::CancelToken;
+pub use crate::search_parallel::ParallelSearchRes;
+pub use crate::search_parallel::SearchStatus;
+pub use crate::search_query::Query;
+pub use crate::search_query::QueryNode;
+pub use crate::search_rank::RankOptions;
+pub use crate::search_replace::apply_replace;
+pub use crate::search_replace::preview_replace_in_dir;
+pub use crate::search_replace::replace_in_text;
+pub use crate::search_replace::undo_replace;
+pub use crate::search_replace::ReplacePlan;
+pub use crate::search_saved::SavedSearch;
+pub use crate::search_saved::SearchHistoryEntry;
+pub use crate::search_saved::SearchStore;
+pub use crate::search_stream::search_in_dir_channel;
+pub use crate::search_stream::search_in_dir_streaming;
+pub use crate::search_tokenizer::find_matches_whole_word;
+pub use crate::search_tokenizer::CjkTokenizer;
+pub use crate::search_tokenizer::Token;
+pub use crate::search_tokenizer::Tokenizer;
+pub use crate::search_tokenizer::WordTokenizer;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SearchFileRes {
-    path: String,
-    matches: Vec<String>,
+    pub path: String,
+    pub matches: Vec<String>,
+    // Best match quality in the file, 1.0 for exact matches, lower for fuzzy near misses
+    #[serde(default)]
+    pub match_score: f32,
+    // Relevance of the file, see `search_rank::rank_results`
+    #[serde(default)]
+    pub score: f64,
+    // Structured positions of `matches`, in the same order
+    #[serde(default)]
+    pub positions: Vec<SearchMatch>,
+    // The matching lines with their context when `SearchOptions::context_lines` is set,
+    // `matches` are then these blocks rendered like `grep -n -C`
+    #[serde(default)]
+    pub line_contexts: Vec<LineContext>,
 }
 
-pub type SearchCallback = dyn Fn(PathBuf, String) -> String;
-const EXT_HTML: &str = "html";
-const EXT_XRTM: &str = "xrtm";
-
-fn prepare_html_like_file(file_path: PathBuf, ext: String) -> String {
-    let mut string = String::new();
-
-    let file = match File::open(&file_path) {
-        Ok(sss) => sss,
-        Err(e) => {
-            error!("File::open error {}", e);
-            return "".to_owned();
-        }
-    };
-    let mut reader = BufReader::new(file);
-    match reader.read_to_string(&mut string) {
-        Ok(_) => "",
-        Err(_) => "",
-    };
+// Receives each result of a streaming search as soon as the file matched, called from the
+// search workers
+pub type SearchCallback = dyn Fn(SearchFileRes) + Send + Sync;
 
-    let mut pure_text = "".to_string();
-    if &ext == EXT_HTML {
-        let delete_tages = ["head".to_string(), "script".to_string()].to_vec();
-        pure_text = process_html(&string, &delete_tages);
-    }
-    if &ext == EXT_XRTM {
-        let delete_tages = [
-            "head".to_string(),
-            "script".to_string(),
-            "scalable_block".to_string(),
-        ]
-        .to_vec();
-        pure_text = process_html(&string, &delete_tages);
-    }
-
-    pure_text
-}
-
-fn extract_multibyte_safe(s: &str, start: usize, end: usize) -> Result<String, &'static str> {
+pub(crate) fn extract_multibyte_safe(
+    s: &str,
+    start: usize,
+    end: usize,
+) -> Result<String, &'static str> {
     fn is_char_boundary(s: &str, byte_pos: usize) -> bool {
         byte_pos == 0 || s.is_char_boundary(byte_pos)
     }
@@ -97,6 +138,44 @@ fn test_safe_byte_slice() {
     }
 }
 
+pub fn wrap_text(
+    string: &str,
+    mat_string: &str,
+    start: usize,
+    end: usize,
+    context_size: usize,
+    wrapper_start: &str,
+    wrapper_end: &str,
+) -> (String, usize) {
+    let left_start = max(0, start.saturating_sub(context_size));
+    let left_end = start;
+    let left_context = match extract_multibyte_safe(string, left_start, left_end) {
+        Ok(s) => s,
+        Err(e) => {
+            debug!("left_context error: {}", e);
+            "".to_owned()
+        }
+    };
+
+    let right_start = end;
+    let right_end = min(string.len(), end + context_size);
+    let right_context = match extract_multibyte_safe(string, right_start, right_end) {
+        Ok(s) => s,
+        Err(e) => {
+            debug!("right_context error: {}", e);
+            "".to_owned()
+        }
+    };
+
+    (
+        format!(
+            "{}{}{}{}{}",
+            left_context, wrapper_start, mat_string, wrapper_end, right_context
+        ),
+        right_context.len(),
+    )
+}
+
 pub fn find_matches(
     string: &str,
     search_plain: &str,
@@ -108,44 +187,6 @@ pub fn find_matches(
 ) -> Result<Vec<String>, io::Error> {
     let mut matches: Vec<String> = Vec::new();
 
-    pub fn wrap_text(
-        string: &str,
-        mat_string: &str,
-        start: usize,
-        end: usize,
-        context_size: usize,
-        wrapper_start: &str,
-        wrapper_end: &str,
-    ) -> (String, usize) {
-        let left_start = max(0, start.saturating_sub(context_size));
-        let left_end = start;
-        let left_context = match extract_multibyte_safe(string, left_start, left_end) {
-            Ok(s) => s,
-            Err(e) => {
-                println!("right_context error: {}", e.to_string());
-                "".to_owned()
-            }
-        };
-
-        let right_start = end;
-        let right_end = min(string.len(), end + context_size);
-        let right_context = match extract_multibyte_safe(string, right_start, right_end) {
-            Ok(s) => s,
-            Err(e) => {
-                println!("right_context error: {}", e.to_string());
-                "".to_owned()
-            }
-        };
-
-        (
-            format!(
-                "{}{}{}{}{}",
-                left_context, wrapper_start, mat_string, wrapper_end, right_context
-            ),
-            right_context.len(),
-        )
-    }
-
     if is_re_mode {
         let re = match search_re {
             Some(r) => r,
@@ -174,7 +215,7 @@ pub fn find_matches(
 
             matches.push(w.0);
         }
-    } else {
+    } else if !search_plain.is_empty() {
         let mut start_index = 0;
         let mut sss = string;
 
@@ -264,134 +305,494 @@ fn test_find_matches_plain() {
     println!("\n\n");
 }
 
-fn search_file_content(
-    file_path: &PathBuf,
-    plain: &str,
-    re: &Option<Regex>,
-    is_re_mode: bool,
-    context_size: usize,
-    wrapper_prefix: &str,
-    wrapper_postfix: &str,
-    html_like_exts: &Vec<String>,
-) -> Result<Vec<String>, io::Error> {
-    let mut string = String::new();
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchMode {
+    Plain,
+    Regex,
+    // Boolean query language, see `search_query::Query`
+    Query,
+    // Words within `SearchOptions::fuzzy_distance` edits, see `search_fuzzy::find_fuzzy_spans`
+    Fuzzy,
+    // Whole words split by `SearchOptions::tokenizer`, case insensitive, see
+    // `search_tokenizer::find_word_spans`
+    Word,
+}
 
-    let ext = match file_path.extension().and_then(|ext| ext.to_str()) {
-        Some(v) => v.to_string(),
-        None => "".to_string(),
-    };
+impl SearchMode {
+    pub fn from_re_mode(is_re_mode: bool) -> SearchMode {
+        if is_re_mode {
+            SearchMode::Regex
+        } else {
+            SearchMode::Plain
+        }
+    }
+}
 
-    if ext.len() > 0 && html_like_exts.contains(&ext) {
-        string = prepare_html_like_file(file_path.to_path_buf(), ext)
-    } else {
-        let file = match File::open(file_path) {
-            Ok(sss) => sss,
-            Err(e) => {
-                error!("File::open error {}", e);
-                return Ok([].to_vec());
-            }
-        };
-        let mut reader = BufReader::new(file);
-        reader.read_to_string(&mut string)?;
+const DEFAULT_MAX_MEMORY: u64 = 64 * 1024 * 1024;
+
+// Options of `search_in_dir_with_options` and `search_in_file_with_options`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SearchOptions {
+    pub mode: SearchMode,
+    pub context_size: usize,
+    // Context in whole lines before and after the matching lines instead of `context_size`
+    // bytes, see `search_match::build_line_contexts`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_lines: Option<usize>,
+    pub wrapper_prefix: String,
+    pub wrapper_postfix: String,
+    pub html_like_exts: Vec<String>,
+    // Used by the plain mode and the terms of the query mode
+    pub plain_match: PlainMatchOptions,
+    // Max Levenshtein distance of the fuzzy mode
+    pub fuzzy_distance: usize,
+    pub ranking: RankOptions,
+    // Used by the parallel search: worker threads (0 for one per CPU), stop after this many
+    // matched files (0 for no limit) and stop after this many milliseconds (0 for no limit)
+    pub threads: usize,
+    pub max_results: usize,
+    pub time_budget_ms: u64,
+    pub filter: FileFilter,
+    // Files with these extensions are searched through their rendered markdown text
+    pub markdown_exts: Vec<String>,
+    pub markdown: MarkdownOptions,
+    // Plain text files bigger than this many bytes are searched in chunks with about this much
+    // memory, other bigger files are skipped. 0 to always read whole files.
+    pub max_memory: u64,
+    // Search the files inside the archives with these extensions, see `search_archive`
+    pub search_archives: bool,
+    pub archive_exts: Vec<String>,
+    // Search through the inverted index stored at this path, updated before each search, see
+    // `search_index::search_in_dir_indexed_with_options`. Archives are not indexed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index_path: Option<String>,
+    // Extractors of the searched files, built from `html_like_exts` and `markdown_exts` if None
+    #[serde(skip)]
+    pub extractors: Option<Arc<ExtractorRegistry>>,
+    // Splits the text into words for the word mode and the index, `CjkTokenizer::new()` if None
+    #[serde(skip)]
+    pub tokenizer: Option<Arc<dyn Tokenizer>>,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        SearchOptions::new()
     }
+}
 
-    match find_matches(
-        &string,
-        &plain,
-        re,
-        is_re_mode,
-        context_size,
-        wrapper_prefix,
-        wrapper_postfix,
-    ) {
-        Ok(results) => Ok(results),
-        Err(e) => Err(io::Error::new(
-            ErrorKind::InvalidInput,
-            format!("find_matches error:{}", e),
-        )),
+impl SearchOptions {
+    pub fn new() -> SearchOptions {
+        SearchOptions {
+            mode: SearchMode::Plain,
+            context_size: 50,
+            context_lines: None,
+            wrapper_prefix: "<b>".to_string(),
+            wrapper_postfix: "</b>".to_string(),
+            html_like_exts: [].to_vec(),
+            plain_match: PlainMatchOptions::new(),
+            fuzzy_distance: 2,
+            ranking: RankOptions::new(),
+            threads: 0,
+            max_results: 0,
+            time_budget_ms: 0,
+            filter: FileFilter::new(),
+            markdown_exts: ["md".to_string(), "markdown".to_string()].to_vec(),
+            markdown: MarkdownOptions::new(),
+            max_memory: DEFAULT_MAX_MEMORY,
+            search_archives: false,
+            archive_exts: ["zip".to_string()].to_vec(),
+            index_path: None,
+            extractors: None,
+            tokenizer: None,
+        }
+    }
+
+    pub(crate) fn from_args(
+        is_re_mode: bool,
+        context_size: usize,
+        wrapper_prefix: &str,
+        wrapper_postfix: &str,
+        html_like_exts: &[String],
+    ) -> SearchOptions {
+        SearchOptions {
+            mode: SearchMode::from_re_mode(is_re_mode),
+            context_size,
+            context_lines: None,
+            wrapper_prefix: wrapper_prefix.to_string(),
+            wrapper_postfix: wrapper_postfix.to_string(),
+            html_like_exts: html_like_exts.to_vec(),
+            plain_match: PlainMatchOptions::new(),
+            fuzzy_distance: 2,
+            ranking: RankOptions::new(),
+            threads: 0,
+            max_results: 0,
+            time_budget_ms: 0,
+            // The functions without options keep searching every file like they always did
+            filter: FileFilter::none(),
+            markdown_exts: [].to_vec(),
+            markdown: MarkdownOptions::new(),
+            max_memory: DEFAULT_MAX_MEMORY,
+            search_archives: false,
+            archive_exts: ["zip".to_string()].to_vec(),
+            index_path: None,
+            extractors: None,
+            tokenizer: None,
+        }
+    }
+
+    pub(crate) fn extractor_registry(&self) -> Arc<ExtractorRegistry> {
+        match &self.extractors {
+            Some(r) => r.clone(),
+            None => Arc::new(ExtractorRegistry::with_defaults(
+                &self.html_like_exts,
+                &self.markdown_exts,
+                &self.markdown,
+            )),
+        }
+    }
+
+    pub(crate) fn tokenizer(&self) -> Arc<dyn Tokenizer> {
+        match &self.tokenizer {
+            Some(t) => t.clone(),
+            None => Arc::new(CjkTokenizer::new()),
+        }
     }
 }
 
-pub fn search_in_dir(
-    dir_path: &PathBuf,
+// The search string compiled once for all files according to the mode, with the extractors
+// reading the files and the tokenizer of the word mode
+pub(crate) struct CompiledSearch {
+    pub re: Option<Regex>,
+    pub query: Option<Query>,
+    pub extractors: Arc<ExtractorRegistry>,
+    pub tokenizer: Arc<dyn Tokenizer>,
+}
+
+pub(crate) fn compile_search(
     search: &str,
-    is_re_mode: bool,
-    context_size: usize,
-    wrapper_prefix: &str,
-    wrapper_postfix: &str,
-    html_like_exts: &Vec<String>,
-) -> Result<Vec<SearchFileRes>, io::Error> {
-    let mut results: Vec<SearchFileRes> = Vec::new();
-    let plain = if is_re_mode { "" } else { search };
-    let re: Option<Regex> = if is_re_mode {
-        match Regex::new(search) {
-            Ok(r) => Some(r),
+    options: &SearchOptions,
+) -> Result<CompiledSearch, io::Error> {
+    let mut compiled = CompiledSearch {
+        re: None,
+        query: None,
+        extractors: options.extractor_registry(),
+        tokenizer: options.tokenizer(),
+    };
 
+    match options.mode {
+        SearchMode::Plain => {}
+        SearchMode::Regex => match Regex::new(search) {
+            Ok(r) => compiled.re = Some(r),
             Err(e) => {
                 return Err(io::Error::new(
                     ErrorKind::InvalidInput,
                     format!("Regex::new error: {:?}", e),
-                ));
+                ))
+            }
+        },
+        SearchMode::Query => compiled.query = Some(Query::parse(search)?),
+        SearchMode::Fuzzy | SearchMode::Word => {}
+    }
+
+    Ok(compiled)
+}
+
+// The byte ranges of the matches in a text, and the best match score. The query mode needs
+// the file info of the text for its file predicates and fails without it.
+pub(crate) fn search_text(
+    string: &str,
+    fields: &[TextField],
+    info: Option<&FileInfo>,
+    search: &str,
+    compiled: &CompiledSearch,
+    options: &SearchOptions,
+) -> Result<(Vec<(usize, usize)>, f32), io::Error> {
+    match options.mode {
+        SearchMode::Fuzzy => {
+            let spans = find_fuzzy_spans(string, search, options.fuzzy_distance);
+            let best = spans.iter().fold(0.0_f32, |acc, s| acc.max(s.score));
+            Ok((spans.into_iter().map(|s| (s.start, s.end)).collect(), best))
+        }
+        SearchMode::Regex => match &compiled.re {
+            Some(re) => Ok((
+                re.find_iter(string).map(|m| (m.start(), m.end())).collect(),
+                1.0,
+            )),
+            None => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("find_matches error:{}", "Regex is None."),
+            )),
+        },
+        SearchMode::Query => match (&compiled.query, info) {
+            (Some(query), Some(info)) => {
+                let spans = query
+                    .find_spans_in_fields(info, string, fields, &options.plain_match)
+                    .unwrap_or_default();
+                Ok((spans, 1.0))
             }
+            (Some(_), None) => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("find_matches error:{}", "File info is None."),
+            )),
+            (None, _) => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("find_matches error:{}", "Query is None."),
+            )),
+        },
+        SearchMode::Plain => Ok((find_plain_spans(string, search, &options.plain_match), 1.0)),
+        SearchMode::Word => Ok((
+            find_word_spans(string, search, compiled.tokenizer.as_ref()),
+            1.0,
+        )),
+    }
+}
+
+#[test]
+fn test_search_text_query() {
+    let mut options = SearchOptions::new();
+    options.mode = SearchMode::Query;
+    let compiled = compile_search("rust ext:md", &options).unwrap();
+    let mut info = FileInfo::new();
+    info.name = "a.md".to_string();
+    info.path = "/notes/a.md".to_string();
+
+    let (spans, _) = search_text("about rust", &[], Some(&info), "", &compiled, &options).unwrap();
+    assert_eq!(spans, [(6, 10)]);
+    info.name = "a.txt".to_string();
+    info.path = "/notes/a.txt".to_string();
+    let (spans, _) = search_text("about rust", &[], Some(&info), "", &compiled, &options).unwrap();
+    assert!(spans.is_empty());
+    assert!(search_text("about rust", &[], None, "", &compiled, &options).is_err());
+}
+
+// The matches of one file before they become a `SearchFileRes`
+pub(crate) struct FileMatches {
+    pub positions: Vec<SearchMatch>,
+    pub match_score: f32,
+    // Length of the searched text, None if the file was skipped without reading it
+    pub text_len: Option<usize>,
+    pub line_contexts: Vec<LineContext>,
+}
+
+impl FileMatches {
+    fn new(
+        string: &str,
+        spans: &[(usize, usize)],
+        match_score: f32,
+        options: &SearchOptions,
+    ) -> Self {
+        FileMatches {
+            positions: build_matches(string, spans, options.context_size),
+            match_score,
+            text_len: Some(string.len()),
+            line_contexts: match options.context_lines {
+                Some(n) => build_line_contexts(string, spans, n),
+                None => [].to_vec(),
+            },
         }
-    } else {
-        None
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    pub fn into_res(self, path: String, options: &SearchOptions) -> SearchFileRes {
+        let matches = if options.context_lines.is_some() {
+            self.line_contexts
+                .iter()
+                .map(|c| c.render(&options.wrapper_prefix, &options.wrapper_postfix))
+                .collect()
+        } else {
+            render_matches(
+                &self.positions,
+                &options.wrapper_prefix,
+                &options.wrapper_postfix,
+            )
+        };
+
+        SearchFileRes {
+            path,
+            matches,
+            positions: self.positions,
+            match_score: self.match_score,
+            score: 0.0,
+            line_contexts: self.line_contexts,
+        }
+    }
+}
+
+pub(crate) fn search_file_content(
+    file_path: &PathBuf,
+    search: &str,
+    compiled: &CompiledSearch,
+    options: &SearchOptions,
+) -> Result<FileMatches, io::Error> {
+    if is_too_big(file_path, options) {
+        let mut head: Vec<u8> = Vec::new();
+        File::open(file_path)?.take(512).read_to_end(&mut head)?;
+        if !compiled.extractors.is_plain_text(file_path, &head) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("file bigger than max_memory: {}", file_path.display()),
+            ));
+        }
+        return search_file_chunked(file_path, search, compiled, options);
+    }
+
+    search_content(
+        || file_info(file_path),
+        || compiled.extractors.extract_file(file_path),
+        search,
+        compiled,
+        options,
+    )
+}
+
+// Search a document given how to get its file info and its text, the file info is only needed
+// by the query mode and the text is not read if the file info already rules the file out
+pub(crate) fn search_content(
+    info: impl FnOnce() -> FileInfo,
+    read: impl FnOnce() -> Result<ExtractedText, io::Error>,
+    search: &str,
+    compiled: &CompiledSearch,
+    options: &SearchOptions,
+) -> Result<FileMatches, io::Error> {
+    let info = match &compiled.query {
+        Some(query) => {
+            let info = info();
+            if query.eval_file_info(&info) == Some(false) {
+                return Ok(FileMatches {
+                    positions: [].to_vec(),
+                    match_score: 0.0,
+                    text_len: None,
+                    line_contexts: [].to_vec(),
+                });
+            }
+            Some(info)
+        }
+        None => None,
     };
 
-    for entry_result in fs::read_dir(dir_path)? {
-        let entry = entry_result?;
-        let path = entry.path();
+    let doc = read()?;
+    let (spans, match_score) = search_text(
+        &doc.text,
+        &doc.fields,
+        info.as_ref(),
+        search,
+        compiled,
+        options,
+    )?;
 
-        if path.is_file() {
-            let sss = match search_file_content(
-                &path,
-                plain,
-                &re,
-                is_re_mode,
-                context_size,
-                &wrapper_prefix,
-                &wrapper_postfix,
-                &html_like_exts,
-            ) {
-                Ok(sss) => sss,
-                Err(e) => {
-                    debug!("process_file error: {}", e);
-                    continue;
-                }
-            };
-
-            if sss.len() > 0 {
-                let search_file_res: SearchFileRes = SearchFileRes {
-                    path: path_buf_to_string(path),
-                    matches: sss,
-                };
-                results.push(search_file_res);
+    Ok(FileMatches::new(&doc.text, &spans, match_score, options))
+}
+
+// Whether a file name matches the search, for the file name boost of the ranking
+pub(crate) fn file_name_matches(
+    name: &str,
+    search: &str,
+    compiled: &CompiledSearch,
+    mode: SearchMode,
+) -> bool {
+    match mode {
+        SearchMode::Regex => compiled.re.as_ref().is_some_and(|re| re.is_match(name)),
+        SearchMode::Query => false,
+        SearchMode::Word => !find_word_spans(name, search, compiled.tokenizer.as_ref()).is_empty(),
+        SearchMode::Plain | SearchMode::Fuzzy => {
+            let search = search.trim().to_lowercase();
+            !search.is_empty() && name.to_lowercase().contains(&search)
+        }
+    }
+}
+
+// The files of a directory to search, with the archives if they are searched. Stops early when
+// `should_stop` returns true.
+pub(crate) fn walk_dir_files(
+    dir_path: &Path,
+    options: &SearchOptions,
+    should_stop: &dyn Fn() -> bool,
+) -> Result<Vec<PathBuf>, io::Error> {
+    let mut walker = DirWalker::new(dir_path, &options.filter)?;
+    if options.search_archives {
+        walker = walker.keep_archive_exts(&options.archive_exts);
+    }
+    walker.walk(should_stop)
+}
+
+// Search a file, or each file of an archive, errors are logged and the file skipped
+pub(crate) fn search_path(
+    path: &Path,
+    search: &str,
+    compiled: &CompiledSearch,
+    options: &SearchOptions,
+    add_res: &mut dyn FnMut(String, FileMatches),
+) {
+    if is_archive(path, options) {
+        if let Err(e) = search_archive(path, search, compiled, options, add_res) {
+            debug!("search_archive error: {}", e);
+        }
+        return;
+    }
+
+    match search_file_content(&path.to_path_buf(), search, compiled, options) {
+        Ok(sss) => add_res(path_buf_to_string(path.to_path_buf()), sss),
+        Err(e) => debug!("process_file error: {}", e),
+    }
+}
+
+pub fn search_in_dir_with_options(
+    dir_path: &Path,
+    search: &str,
+    options: &SearchOptions,
+) -> Result<Vec<SearchFileRes>, io::Error> {
+    if let Some(index_path) = &options.index_path {
+        return search_in_dir_indexed_with_options(dir_path, index_path, search, options);
+    }
+
+    let compiled = compile_search(search, options)?;
+    let mut stats = CorpusStats::default();
+    let mut results: Vec<SearchFileRes> = Vec::new();
+
+    for path in walk_dir_files(dir_path, options, &|| false)? {
+        search_path(&path, search, &compiled, options, &mut |path_str, sss| {
+            if let Some(len) = sss.text_len {
+                stats.add_file(&path_str, len, !sss.is_empty());
             }
-        } else if path.is_dir() {
-            let sss = match search_in_dir(
-                &path,
-                search,
-                is_re_mode,
-                context_size,
-                wrapper_prefix,
-                wrapper_postfix,
-                &html_like_exts,
-            ) {
-                Ok(sss) => sss,
-                Err(e) => {
-                    error!("search_document error {}", e);
-                    continue;
-                }
-            };
-            if sss.len() > 0 {
-                results.extend(sss);
+            if !sss.is_empty() {
+                results.push(sss.into_res(path_str, options));
             }
-        }
+        });
     }
+    rank_results(
+        &mut results,
+        &stats,
+        &|name| file_name_matches(name, search, &compiled, options.mode),
+        &options.ranking,
+    );
 
     Ok(results)
 }
 
+pub fn search_in_dir(
+    dir_path: &Path,
+    search: &str,
+    is_re_mode: bool,
+    context_size: usize,
+    wrapper_prefix: &str,
+    wrapper_postfix: &str,
+    html_like_exts: &[String],
+) -> Result<Vec<SearchFileRes>, io::Error> {
+    let options = SearchOptions::from_args(
+        is_re_mode,
+        context_size,
+        wrapper_prefix,
+        wrapper_postfix,
+        html_like_exts,
+    );
+
+    search_in_dir_with_options(dir_path, search, &options)
+}
+
 #[test]
 fn test_search_document_dir_re() {
     let dir_path = PathBuf::from("/home/xxx/Documents/fivim/user_files");
@@ -429,81 +830,156 @@ fn test_search_document_dir_re() {
     println!("\n\n");
 }
 
-pub fn search_in_file(
+#[test]
+fn test_search_in_dir_unfiltered() {
+    let dir = std::env::temp_dir().join("fivim_rs_utils_test_search_unfiltered");
+    let _ = std::fs::remove_dir_all(&dir);
+    for (name, content) in [
+        (".gitignore", "build/\n"),
+        ("a.md", "needle"),
+        ("build/b.md", "needle"),
+        (".git/c.md", "needle"),
+    ] {
+        let _ = crate::fs_file::write_str(&path_buf_to_string(dir.join(name)), content);
+    }
+
+    let exts: Vec<String> = [].to_vec();
+    let results = search_in_dir(&dir, "needle", false, 10, "<b>", "</b>", &exts).unwrap();
+    assert_eq!(results.len(), 3);
+    let results = search_in_dir_with_options(&dir, "needle", &SearchOptions::new()).unwrap();
+    assert_eq!(results.len(), 1);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_search_in_dir_with_options() {
+    let dir = std::env::temp_dir().join("fivim_rs_utils_test_search_options");
+    let _ = std::fs::remove_dir_all(&dir);
+    let _ = crate::fs_file::write_str(
+        &path_buf_to_string(dir.join("cv.md")),
+        "My Résumé is attached",
+    );
+
+    let mut options = SearchOptions::new();
+    options.context_size = 3;
+    let results = search_in_dir_with_options(&dir, "resume", &options).unwrap();
+    assert!(results.is_empty());
+
+    options.plain_match.ignore_case = true;
+    options.plain_match.ignore_accents = true;
+    let results = search_in_dir_with_options(&dir, "resume", &options).unwrap();
+    assert_eq!(results[0].matches, ["My <b>Résumé</b> is"]);
+    assert_eq!(results[0].positions[0].char_start, 3);
+
+    options.mode = SearchMode::Query;
+    let results = search_in_dir_with_options(&dir, "resume -draft ext:md", &options).unwrap();
+    assert_eq!(results[0].matches, ["My <b>Résumé</b> is"]);
+    let results = search_in_dir_with_options(&dir, "resume ext:txt", &options).unwrap();
+    assert!(results.is_empty());
+
+    let _ = crate::fs_file::write_str(
+        &path_buf_to_string(dir.join("rust.md")),
+        "---\ntags: [lang]\n---\n# Rust **notes**\nrust [book](https://rust-lang.org)",
+    );
+    let results = search_in_dir_with_options(&dir, "heading:notes tag:lang", &options).unwrap();
+    assert_eq!(
+        results[0].matches,
+        ["<b>lang</b>\nRu", "st <b>notes</b>\nru"]
+    );
+    let results = search_in_dir_with_options(&dir, "rust-lang", &options).unwrap();
+    assert!(results.is_empty());
+    let _ = std::fs::remove_file(dir.join("rust.md"));
+
+    options.mode = SearchMode::Fuzzy;
+    let results = search_in_dir_with_options(&dir, "atached", &options).unwrap();
+    assert_eq!(results[0].matches, ["is <b>attached</b>"]);
+    assert!(results[0].match_score < 1.0);
+    assert!(results[0].score > 0.0);
+
+    options.mode = SearchMode::Regex;
+    options.context_lines = Some(1);
+    let _ = crate::fs_file::write_str(
+        &path_buf_to_string(dir.join("cv.md")),
+        "My Résumé\nis attached\nand\nfor\nsigned\nby me",
+    );
+    let results = search_in_dir_with_options(&dir, r"attached|me$", &options).unwrap();
+    assert_eq!(
+        results[0].matches,
+        [
+            "1-My Résumé\n2:is <b>attached</b>\n3-and",
+            "5-signed\n6:by <b>me</b>"
+        ]
+    );
+    assert_eq!(results[0].positions.len(), 2);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+pub fn search_in_file_with_options(
     file_path: &PathBuf,
     search: &str,
-    is_re_mode: bool,
-    context_size: usize,
-    wrapper_prefix: &str,
-    wrapper_postfix: &str,
-    html_like_exts: &Vec<String>,
+    options: &SearchOptions,
 ) -> Result<Vec<SearchFileRes>, io::Error> {
     let mut results: Vec<SearchFileRes> = Vec::new();
-    let plain = if is_re_mode { "" } else { search };
-    let re: Option<Regex> = if is_re_mode {
-        match Regex::new(search) {
-            Ok(r) => Some(r),
+    let compiled = compile_search(search, options)?;
+
+    let mut stats = CorpusStats::default();
+    let mut add_res = |path: String, sss: FileMatches| {
+        if !sss.is_empty() {
+            stats.add_file(&path, sss.text_len.unwrap_or(0), true);
+            results.push(sss.into_res(path, options));
+        }
+    };
 
+    if is_archive(file_path, options) {
+        search_archive(file_path, search, &compiled, options, &mut add_res)?;
+    } else {
+        let sss = match search_file_content(file_path, search, &compiled, options) {
+            Ok(sss) => sss,
             Err(e) => {
                 return Err(io::Error::new(
                     ErrorKind::InvalidInput,
-                    format!("Regex::new error: {:?}", e),
+                    format!("process_file error: {:?}", e),
                 ));
             }
-        }
-    } else {
-        None
-    };
-
-    let sss = match search_file_content(
-        &file_path,
-        plain,
-        &re,
-        is_re_mode,
-        context_size,
-        &wrapper_prefix,
-        &wrapper_postfix,
-        &html_like_exts,
-    ) {
-        Ok(sss) => sss,
-        Err(e) => {
-            return Err(io::Error::new(
-                ErrorKind::InvalidInput,
-                format!("process_file error: {:?}", e),
-            ));
-        }
-    };
-
-    if sss.len() > 0 {
-        let search_file_res: SearchFileRes = SearchFileRes {
-            path: path_buf_to_string(file_path.to_path_buf()),
-            matches: sss,
         };
-        results.push(search_file_res);
+        add_res(path_buf_to_string(file_path.to_path_buf()), sss);
     }
 
+    rank_results(
+        &mut results,
+        &stats,
+        &|name| file_name_matches(name, search, &compiled, options.mode),
+        &options.ranking,
+    );
+
     Ok(results)
 }
 
-pub fn process_html(html: &str, delete_tages: &Vec<String>) -> String {
-    let br_re = Regex::new(r"<br[^>]*>").unwrap();
-    let mut html_str = br_re.replace_all(html, "\n").to_string();
-
-    for tag in delete_tages {
-        let regex_str = format!(r#"<{}[^>]*>(.*?)<\/script>"#, tag);
-        let re = Regex::new(&regex_str).unwrap();
-        html_str = re.replace_all(&html_str, "").to_string();
-    }
-
-    let tag_re = Regex::new(r"<[^>]+>").unwrap();
-    html_str = tag_re.replace_all(&html_str, "").to_string();
-
-    let whitespace_re = Regex::new(r"\s+").unwrap();
-    let result = whitespace_re.replace_all(&html_str, " ");
+pub fn search_in_file(
+    file_path: &PathBuf,
+    search: &str,
+    is_re_mode: bool,
+    context_size: usize,
+    wrapper_prefix: &str,
+    wrapper_postfix: &str,
+    html_like_exts: &[String],
+) -> Result<Vec<SearchFileRes>, io::Error> {
+    let options = SearchOptions::from_args(
+        is_re_mode,
+        context_size,
+        wrapper_prefix,
+        wrapper_postfix,
+        html_like_exts,
+    );
 
-    let res = result.to_string();
+    search_in_file_with_options(file_path, search, &options)
+}
 
-    decode_html_entities(&res).to_string()
+// The text of an html document without the `delete_tages` elements, see `extract_html_text`
+pub fn process_html(html: &str, delete_tages: &[String]) -> String {
+    extract_html_text(html, &HtmlTextOptions::with_delete_tags(delete_tages)).text
 }
 
 #[test]
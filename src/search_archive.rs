@@ -0,0 +1,212 @@
+use chrono::{FixedOffset, TimeZone};
+use log::debug;
+use std::fs::File;
+use std::io::{self, ErrorKind, Read};
+use std::path::Path;
+
+use crate::fs::path_buf_to_string;
+use crate::fs_file::{self, FileInfo};
+use crate::search::{search_content, CompiledSearch, FileMatches, SearchOptions};
+use crate::search_filter::DirWalker;
+
+// Separates the path of an archive from the path of a file inside it, like `backup.zip!/notes/a.md`
+pub const ARCHIVE_PATH_SEPARATOR: &str = "!/";
+
+// Split a search result path into the archive path and the path inside the archive
+pub fn split_archive_path(path: &str) -> Option<(&str, &str)> {
+    path.split_once(ARCHIVE_PATH_SEPARATOR)
+}
+
+pub(crate) fn is_archive(file_path: &Path, options: &SearchOptions) -> bool {
+    if !options.search_archives {
+        return false;
+    }
+
+    let ext = file_path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_lowercase();
+    options.archive_exts.iter().any(|e| e.to_lowercase() == ext)
+}
+
+fn entry_info(path: &str, name: &Path, size: u64, modified: zip::DateTime) -> FileInfo {
+    let mut info = FileInfo::new();
+    info.name = name
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("")
+        .to_string();
+    info.path = path.to_string();
+    info.size = size;
+    if let Some(t) = FixedOffset::east_opt(0).and_then(|tz| {
+        tz.with_ymd_and_hms(
+            modified.year() as i32,
+            modified.month() as u32,
+            modified.day() as u32,
+            modified.hour() as u32,
+            modified.minute() as u32,
+            modified.second() as u32,
+        )
+        .single()
+    }) {
+        info.created = t;
+        info.accessed = t;
+        info.modified = t;
+    }
+    info
+}
+
+// Search the files of a zip archive one at a time in memory, nothing is extracted to disk.
+// The filter of the options applies to the paths inside the archive, `on_entry` receives the
+// `archive!/inner/path` of each searched file with its matches.
+pub(crate) fn search_archive(
+    archive_path: &Path,
+    search: &str,
+    compiled: &CompiledSearch,
+    options: &SearchOptions,
+    on_entry: &mut dyn FnMut(String, FileMatches),
+) -> Result<(), io::Error> {
+    let file = File::open(archive_path)?;
+    let mut archive = zip::ZipArchive::new(file)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
+    let walker = DirWalker::new(Path::new(""), &options.filter)?;
+    let archive_str = path_buf_to_string(archive_path.to_path_buf());
+    // Entries are read whole in memory, the sizes in the zip headers are not trusted
+    let max_size = [options.filter.max_file_size, options.max_memory]
+        .into_iter()
+        .filter(|s| *s > 0)
+        .min()
+        .unwrap_or(u64::MAX);
+
+    for i in 0..archive.len() {
+        let mut entry = match archive.by_index(i) {
+            Ok(e) => e,
+            Err(e) => {
+                debug!("zip entry error: {}", e);
+                continue;
+            }
+        };
+        if entry.is_dir() {
+            continue;
+        }
+        // Entries with `..` or absolute paths are not searched
+        let name = match entry.enclosed_name() {
+            Some(n) => n.to_path_buf(),
+            None => continue,
+        };
+        if !walker.matches_entry(&name) {
+            continue;
+        }
+        if entry.size() > max_size {
+            continue;
+        }
+
+        let mut bytes: Vec<u8> = Vec::new();
+        if let Err(e) = (&mut entry)
+            .take(max_size.saturating_add(1))
+            .read_to_end(&mut bytes)
+        {
+            debug!("zip entry read error: {}", e);
+            continue;
+        }
+        if bytes.len() as u64 > max_size {
+            debug!("zip entry bigger than its header size: {}", entry.name());
+            continue;
+        }
+        if options.filter.skip_binary && fs_file::is_binary_bytes(&bytes) {
+            continue;
+        }
+
+        let entry_path = format!(
+            "{}{}{}",
+            archive_str,
+            ARCHIVE_PATH_SEPARATOR,
+            entry.name().trim_start_matches('/')
+        );
+        let size = entry.size();
+        let modified = entry.last_modified();
+        let res = search_content(
+            || entry_info(&entry_path, &name, size, modified),
+            || compiled.extractors.extract_bytes(&name, &bytes),
+            search,
+            compiled,
+            options,
+        );
+        match res {
+            Ok(sss) => on_entry(entry_path, sss),
+            Err(e) => debug!("process_file error: {}", e),
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_search_archive() {
+    use crate::search::{search_in_dir_with_options, search_in_file_with_options};
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    let dir = std::env::temp_dir().join("fivim_rs_utils_test_search_archive");
+    let _ = std::fs::remove_dir_all(&dir);
+    let _ = std::fs::create_dir_all(&dir);
+
+    let zip_path = dir.join("backup.zip");
+    let mut writer = zip::ZipWriter::new(File::create(&zip_path).unwrap());
+    let opts = zip::write::FileOptions::default();
+    for (name, content) in [
+        ("notes/a.md", "# Rust\nold note about rust"),
+        ("notes/b.txt", "nothing here"),
+        ("page.html", "<p>rust page</p>"),
+        ("../evil.txt", "rust"),
+    ] {
+        writer.start_file(name, opts).unwrap();
+        writer.write_all(content.as_bytes()).unwrap();
+    }
+    writer.finish().unwrap();
+    let _ = fs_file::write_str(&path_buf_to_string(dir.join("c.txt")), "rust outside");
+
+    let mut options = SearchOptions::new();
+    options.html_like_exts = ["html".to_string()].to_vec();
+    let paths = |options: &SearchOptions| -> Vec<String> {
+        let mut res: Vec<String> = search_in_dir_with_options(&dir, "rust", options)
+            .unwrap()
+            .iter()
+            .map(|r| {
+                r.path
+                    .strip_prefix(&path_buf_to_string(dir.clone()))
+                    .unwrap()
+                    .to_string()
+            })
+            .collect();
+        res.sort();
+        res
+    };
+    assert_eq!(paths(&options), ["/c.txt"]);
+
+    options.search_archives = true;
+    assert_eq!(
+        paths(&options),
+        [
+            "/backup.zip!/notes/a.md",
+            "/backup.zip!/page.html",
+            "/c.txt"
+        ]
+    );
+
+    options.filter.include_globs = ["*.md".to_string()].to_vec();
+    assert_eq!(paths(&options), ["/backup.zip!/notes/a.md"]);
+    options.max_memory = 16;
+    assert!(paths(&options).is_empty());
+    options.max_memory = 0;
+    let res = search_in_file_with_options(&PathBuf::from(&zip_path), "rust", &options).unwrap();
+    assert_eq!(res.len(), 1);
+    assert_eq!(res[0].positions.len(), 1);
+    assert_eq!(
+        split_archive_path(&res[0].path),
+        Some((path_buf_to_string(zip_path).as_str(), "notes/a.md"))
+    );
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,269 @@
+use encoding_rs::Decoder;
+use std::fs::File;
+use std::io::{self, ErrorKind, Read};
+use std::path::Path;
+
+use crate::fs_encoding::detect_encoding;
+use crate::search::{search_text, CompiledSearch, FileMatches, SearchOptions};
+use crate::search_match::{build_line_contexts, build_matches, LineContext, SearchMatch};
+
+// Smallest overlap kept between two windows, matches longer than the overlap minus the
+// context size may be missed or cut
+const MIN_OVERLAP: usize = 4096;
+
+// Whether a file is too big to be read at once under `SearchOptions::max_memory`
+pub(crate) fn is_too_big(file_path: &Path, options: &SearchOptions) -> bool {
+    options.max_memory > 0
+        && file_path
+            .metadata()
+            .is_ok_and(|m| m.len() > options.max_memory)
+}
+
+// Where the window starts in the whole decoded text
+#[derive(Default)]
+struct WindowBase {
+    byte: usize,
+    char: usize,
+    // Lines before the window
+    line: usize,
+    // Chars between the last line break and the window
+    column: usize,
+}
+
+impl WindowBase {
+    fn advance(&mut self, dropped: &str) {
+        self.byte += dropped.len();
+        self.char += dropped.chars().count();
+        match dropped.rfind('\n') {
+            Some(i) => {
+                self.line += dropped.matches('\n').count();
+                self.column = dropped[i + 1..].chars().count();
+            }
+            None => self.column += dropped.chars().count(),
+        }
+    }
+
+    fn shift(&self, m: &mut SearchMatch) {
+        if m.line == 1 {
+            m.column += self.column;
+        }
+        m.line += self.line;
+        m.byte_start += self.byte;
+        m.byte_end += self.byte;
+        m.char_start += self.char;
+        m.char_end += self.char;
+    }
+}
+
+fn floor_char_boundary(s: &str, mut i: usize) -> usize {
+    while i > 0 && !s.is_char_boundary(i) {
+        i -= 1;
+    }
+    i
+}
+
+// The start of the line `lines` lines before the one containing the byte `i`
+fn line_start_back(s: &str, i: usize, lines: usize) -> usize {
+    let mut start = s[..i].rfind('\n').map_or(0, |p| p + 1);
+    for _ in 0..lines {
+        if start == 0 {
+            break;
+        }
+        start = s[..start - 1].rfind('\n').map_or(0, |p| p + 1);
+    }
+    start
+}
+
+// Add a block of a window to the blocks of the previous windows, merged with the last one
+// when they overlap or touch. An overlapping line keeps the matches of either block.
+fn push_line_context(contexts: &mut Vec<LineContext>, block: LineContext) {
+    if let Some(last) = contexts.last_mut() {
+        let first = last.lines[0].number;
+        let last_number = last.lines[last.lines.len() - 1].number;
+        if block.lines[0].number <= last_number + 1 {
+            for line in block.lines {
+                match line
+                    .number
+                    .checked_sub(first)
+                    .and_then(|i| last.lines.get_mut(i))
+                {
+                    Some(l) if l.ranges.is_empty() => l.ranges = line.ranges,
+                    Some(_) => {}
+                    None => last.lines.push(line),
+                }
+            }
+            return;
+        }
+    }
+    contexts.push(block);
+}
+
+// Search a plain text file without loading it whole: it is decoded in chunks of about a
+// quarter of `max_memory` into a sliding window, which keeps the end of the previous window
+// so that matches spanning two chunks and their context are found. Positions are in the
+// decoded text like for whole files. The query mode needs the whole text and is not supported.
+// With `context_lines` the windows are cut at line starts and keep the context lines, a line
+// longer than the overlap makes the window grow.
+pub(crate) fn search_file_chunked(
+    file_path: &Path,
+    search: &str,
+    compiled: &CompiledSearch,
+    options: &SearchOptions,
+) -> Result<FileMatches, io::Error> {
+    if compiled.query.is_some() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "the query mode can not search files bigger than max_memory",
+        ));
+    }
+
+    let chunk_size = (options.max_memory as usize / 4).max(MIN_OVERLAP);
+    let overlap = (options.context_size * 2 + search.len() * 4)
+        .max(MIN_OVERLAP)
+        .min(chunk_size);
+
+    let mut file = File::open(file_path)?;
+    let mut buf: Vec<u8> = vec![0; chunk_size];
+    let mut decoder: Option<Decoder> = None;
+
+    let mut window = String::new();
+    let mut base = WindowBase::default();
+    // Matches starting before this offset of the window were found in the previous window
+    let mut new_from = 0;
+    let mut positions: Vec<SearchMatch> = Vec::new();
+    let mut line_contexts: Vec<LineContext> = Vec::new();
+    let mut match_score = 0.0_f32;
+
+    loop {
+        let n = file.read(&mut buf)?;
+        let last = n == 0;
+        let decoder = decoder.get_or_insert_with(|| detect_encoding(&buf[..n]).new_decoder());
+        window.reserve(decoder.max_utf8_buffer_length(n).unwrap_or(n * 3));
+        let _ = decoder.decode_to_string(&buf[..n], &mut window, last);
+
+        // Matches starting in the last `overlap` bytes wait for the next window, and with line
+        // contexts those whose context lines are not all read yet
+        let commit = if last {
+            window.len()
+        } else {
+            let commit = floor_char_boundary(&window, window.len().saturating_sub(overlap));
+            match options.context_lines {
+                Some(n) => line_start_back(&window, commit, 0).min(line_start_back(
+                    &window,
+                    window.len(),
+                    n,
+                )),
+                None => commit,
+            }
+        };
+        if commit > new_from || last {
+            let (spans, score) = search_text(&window, &[], None, search, compiled, options)?;
+            let spans: Vec<(usize, usize)> = spans
+                .into_iter()
+                .filter(|s| s.0 >= new_from && (s.0 < commit || last))
+                .collect();
+            if !spans.is_empty() {
+                match_score = match_score.max(score);
+            }
+            for mut m in build_matches(&window, &spans, options.context_size) {
+                base.shift(&mut m);
+                positions.push(m);
+            }
+            if let Some(n) = options.context_lines {
+                for mut block in build_line_contexts(&window, &spans, n) {
+                    for line in &mut block.lines {
+                        line.number += base.line;
+                    }
+                    push_line_context(&mut line_contexts, block);
+                }
+            }
+        }
+        if last {
+            break;
+        }
+
+        // Keep the context before the first match of the next window, and at least one char so
+        // that `^` and `\b` do not match at the start of the window
+        let keep_from = match options.context_lines {
+            Some(n) => line_start_back(&window, commit, n.max(1)),
+            None => {
+                floor_char_boundary(&window, commit.saturating_sub(options.context_size.max(1)))
+            }
+        };
+        if keep_from > 0 {
+            base.advance(&window[..keep_from]);
+            window.drain(..keep_from);
+        }
+        new_from = commit.max(new_from) - keep_from;
+    }
+
+    Ok(FileMatches {
+        positions,
+        match_score,
+        text_len: Some(base.byte + window.len()),
+        line_contexts,
+    })
+}
+
+#[test]
+fn test_search_file_chunked() {
+    use crate::search::{compile_search, search_in_file_with_options, SearchMode};
+    use std::path::PathBuf;
+
+    let dir = std::env::temp_dir().join("fivim_rs_utils_test_search_chunked");
+    let _ = std::fs::remove_dir_all(&dir);
+    let _ = std::fs::create_dir_all(&dir);
+    let file_path = dir.join("big.log");
+
+    // Matches at all the offsets around the chunk boundaries, with multibyte chars
+    let mut content = String::new();
+    for i in 0..3000 {
+        content.push_str(&format!("line {} 日志 needle{}\n", i, i % 7));
+    }
+    std::fs::write(&file_path, &content).unwrap();
+
+    let mut options = SearchOptions::new();
+    options.context_size = 10;
+    let whole = search_in_file_with_options(&file_path, "needle3", &options).unwrap();
+
+    options.max_memory = 16 * 1024;
+    assert!(is_too_big(&file_path, &options));
+    let chunked = search_in_file_with_options(&file_path, "needle3", &options).unwrap();
+    assert_eq!(whole[0].positions.len(), 3000 / 7 + 1);
+    assert_eq!(chunked[0].positions, whole[0].positions);
+    assert_eq!(chunked[0].matches, whole[0].matches);
+
+    options.mode = SearchMode::Regex;
+    let compiled = compile_search(r"needle\d\nline 2999", &options).unwrap();
+    let res = search_file_chunked(&PathBuf::from(&file_path), "", &compiled, &options).unwrap();
+    assert_eq!(res.positions.len(), 1);
+    assert_eq!((res.positions[0].line, res.positions[0].column), (2999, 14));
+
+    // Anchors are not matched at the start of a window
+    let compiled = compile_search(r"^line|\bneedle3", &options).unwrap();
+    options.context_size = 0;
+    let res = search_file_chunked(&PathBuf::from(&file_path), "", &compiled, &options).unwrap();
+    assert_eq!(res.positions.len(), 3000 / 7 + 2);
+    assert_eq!(res.positions[0].byte_start, 0);
+
+    // Line contexts across the windows, like for the whole file
+    options.mode = SearchMode::Plain;
+    options.context_lines = Some(1);
+    options.max_memory = 0;
+    let whole = search_in_file_with_options(&file_path, "needle", &options).unwrap();
+    options.max_memory = 16 * 1024;
+    let chunked = search_in_file_with_options(&file_path, "needle", &options).unwrap();
+    assert_eq!(chunked[0].positions.len(), 3000);
+    assert_eq!(chunked[0].line_contexts, whole[0].line_contexts);
+    assert_eq!(chunked[0].matches, whole[0].matches);
+    options.max_memory = 0;
+    let whole = search_in_file_with_options(&file_path, "needle3", &options).unwrap();
+    options.max_memory = 16 * 1024;
+    let chunked = search_in_file_with_options(&file_path, "needle3", &options).unwrap();
+    assert_eq!(chunked[0].line_contexts, whole[0].line_contexts);
+
+    options.mode = SearchMode::Query;
+    assert!(search_in_file_with_options(&file_path, "needle3", &options).is_err());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,241 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::search::{
+    compile_search, file_name_matches, search_path, walk_dir_files, SearchFileRes, SearchOptions,
+};
+use crate::search_rank::{rank_results, CorpusStats};
+
+// A file matching in both result sets whose snippets changed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChangedResult {
+    pub res: SearchFileRes,
+    pub added_matches: Vec<String>,
+    pub removed_matches: Vec<String>,
+}
+
+// What changed between two runs of the same search
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SearchResultDiff {
+    // Files matching now but not before
+    pub added: Vec<SearchFileRes>,
+    // Files matching before but not now
+    pub removed: Vec<SearchFileRes>,
+    pub changed: Vec<ChangedResult>,
+    // Paths of the files matching in both with the same snippets
+    pub unchanged: Vec<String>,
+}
+
+impl SearchResultDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+// The snippets of `a` missing from `b`, repeated snippets are counted
+fn missing_matches(a: &[String], b: &[String]) -> Vec<String> {
+    let mut counts: HashMap<&String, usize> = HashMap::new();
+    for m in b {
+        *counts.entry(m).or_default() += 1;
+    }
+
+    let mut res: Vec<String> = Vec::new();
+    for m in a {
+        match counts.get_mut(m) {
+            Some(c) if *c > 0 => *c -= 1,
+            _ => res.push(m.clone()),
+        }
+    }
+    res
+}
+
+// Compare the results of two runs by path and by the snippets of each file, in the order of
+// `new` then `old`
+pub fn diff_results(old: &[SearchFileRes], new: &[SearchFileRes]) -> SearchResultDiff {
+    let old_by_path: HashMap<&str, &SearchFileRes> =
+        old.iter().map(|r| (r.path.as_str(), r)).collect();
+    let new_by_path: HashMap<&str, &SearchFileRes> =
+        new.iter().map(|r| (r.path.as_str(), r)).collect();
+
+    let mut diff = SearchResultDiff::default();
+    for res in new {
+        let prev = match old_by_path.get(res.path.as_str()) {
+            Some(p) => p,
+            None => {
+                diff.added.push(res.clone());
+                continue;
+            }
+        };
+
+        let added_matches = missing_matches(&res.matches, &prev.matches);
+        let removed_matches = missing_matches(&prev.matches, &res.matches);
+        if added_matches.is_empty() && removed_matches.is_empty() {
+            diff.unchanged.push(res.path.clone());
+        } else {
+            diff.changed.push(ChangedResult {
+                res: res.clone(),
+                added_matches,
+                removed_matches,
+            });
+        }
+    }
+    for res in old {
+        if !new_by_path.contains_key(res.path.as_str()) {
+            diff.removed.push(res.clone());
+        }
+    }
+
+    diff
+}
+
+// The results of one walked file, several for an archive
+#[derive(Debug, Clone)]
+struct WatchedFile {
+    modified: Option<SystemTime>,
+    size: u64,
+    // Path and text length of each searched file
+    text_lens: Vec<(String, usize)>,
+    results: Vec<SearchFileRes>,
+}
+
+fn file_stamp(path: &Path) -> (Option<SystemTime>, u64) {
+    match path.metadata() {
+        Ok(m) => (m.modified().ok(), m.len()),
+        Err(_) => (None, 0),
+    }
+}
+
+// A pinned search re-run incrementally: only the files added or modified since the last run,
+// by modification time and size, are searched again
+#[derive(Debug, Clone)]
+pub struct SearchWatch {
+    pub dir_path: PathBuf,
+    pub search: String,
+    pub options: SearchOptions,
+    files: BTreeMap<PathBuf, WatchedFile>,
+    results: Vec<SearchFileRes>,
+}
+
+impl SearchWatch {
+    // The first run is done by the first `refresh`, which reports all the results as added
+    pub fn new(dir_path: &Path, search: &str, options: &SearchOptions) -> SearchWatch {
+        SearchWatch {
+            dir_path: dir_path.to_path_buf(),
+            search: search.to_string(),
+            options: options.clone(),
+            files: BTreeMap::new(),
+            results: [].to_vec(),
+        }
+    }
+
+    // The ranked results of the last refresh
+    pub fn results(&self) -> &[SearchFileRes] {
+        &self.results
+    }
+
+    pub fn refresh(&mut self) -> Result<SearchResultDiff, io::Error> {
+        let compiled = compile_search(&self.search, &self.options)?;
+        let mut files: BTreeMap<PathBuf, WatchedFile> = BTreeMap::new();
+
+        for path in walk_dir_files(&self.dir_path, &self.options, &|| false)? {
+            let (modified, size) = file_stamp(&path);
+            if let Some(prev) = self.files.remove(&path) {
+                if modified.is_some() && prev.modified == modified && prev.size == size {
+                    files.insert(path, prev);
+                    continue;
+                }
+            }
+
+            let mut watched = WatchedFile {
+                modified,
+                size,
+                text_lens: [].to_vec(),
+                results: [].to_vec(),
+            };
+            search_path(
+                &path,
+                &self.search,
+                &compiled,
+                &self.options,
+                &mut |path_str, sss| {
+                    if let Some(len) = sss.text_len {
+                        watched.text_lens.push((path_str.clone(), len));
+                    }
+                    if !sss.is_empty() {
+                        watched.results.push(sss.into_res(path_str, &self.options));
+                    }
+                },
+            );
+            files.insert(path, watched);
+        }
+
+        let mut stats = CorpusStats::default();
+        let mut results: Vec<SearchFileRes> = Vec::new();
+        for watched in files.values() {
+            for (path, len) in &watched.text_lens {
+                let is_matched = watched.results.iter().any(|r| &r.path == path);
+                stats.add_file(path, *len, is_matched);
+            }
+            results.extend(watched.results.iter().cloned());
+        }
+        rank_results(
+            &mut results,
+            &stats,
+            &|name| file_name_matches(name, &self.search, &compiled, self.options.mode),
+            &self.options.ranking,
+        );
+
+        let diff = diff_results(&self.results, &results);
+        self.files = files;
+        self.results = results;
+        Ok(diff)
+    }
+
+    // Files walked by the last refresh
+    pub fn file_count(&self) -> usize {
+        self.files.len()
+    }
+}
+
+#[test]
+fn test_search_diff() {
+    use crate::fs::path_buf_to_string;
+    use crate::fs_file;
+
+    let dir = std::env::temp_dir().join("fivim_rs_utils_test_search_diff");
+    let _ = std::fs::remove_dir_all(&dir);
+    let write = |name: &str, content: &str| {
+        let _ = fs_file::write_str(&path_buf_to_string(dir.join(name)), content);
+    };
+    write("a.md", "rust one");
+    write("b.md", "rust two");
+    write("c.md", "nothing");
+
+    let mut options = SearchOptions::new();
+    options.context_size = 4;
+    let mut watch = SearchWatch::new(&dir, "rust", &options);
+    let diff = watch.refresh().unwrap();
+    assert_eq!(diff.added.len(), 2);
+    assert_eq!(watch.file_count(), 3);
+    assert!(watch.refresh().unwrap().is_empty());
+
+    write("a.md", "rust one rust");
+    let _ = std::fs::remove_file(dir.join("b.md"));
+    write("c.md", "now rust");
+    let diff = watch.refresh().unwrap();
+    let name = |r: &SearchFileRes| r.path.rsplit('/').next().unwrap().to_string();
+    assert_eq!(diff.added.iter().map(name).collect::<Vec<_>>(), ["c.md"]);
+    assert_eq!(diff.removed.iter().map(name).collect::<Vec<_>>(), ["b.md"]);
+    assert_eq!(diff.changed.len(), 1);
+    assert_eq!(diff.changed[0].added_matches, ["one <b>rust</b>"]);
+    assert!(diff.changed[0].removed_matches.is_empty());
+    assert_eq!(watch.results().len(), 2);
+
+    let diff = diff_results(watch.results(), watch.results());
+    assert_eq!(diff.unchanged.len(), 2);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
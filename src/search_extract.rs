@@ -0,0 +1,365 @@
+use encoding_rs::Encoding;
+use log::error;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::fs_encoding::decode_text;
+use crate::search_html::{extract_html_text, ExtractedText, HtmlTextOptions, FIELD_HEADING};
+use crate::search_markdown::{extract_markdown, MarkdownOptions};
+
+const EXT_XRTM: &str = "xrtm";
+
+pub const MIME_HTML: &str = "text/html";
+pub const MIME_JSON: &str = "application/json";
+pub const MIME_MARKDOWN: &str = "text/markdown";
+pub const MIME_PDF: &str = "application/pdf";
+pub const MIME_ZIP: &str = "application/zip";
+
+// Turns the content of a file into searchable text
+pub trait Extractor: Send + Sync {
+    fn extract(&self, bytes: &[u8]) -> Result<ExtractedText, io::Error>;
+}
+
+// The text in its detected encoding, see `fs_encoding::detect_encoding`
+fn decode(bytes: &[u8]) -> String {
+    decode_text(bytes).0
+}
+
+// The text as is, in the declared encoding or else the detected one
+#[derive(Debug, Clone, Default)]
+pub struct PlainTextExtractor {
+    encoding: Option<&'static Encoding>,
+}
+
+impl PlainTextExtractor {
+    pub fn new() -> PlainTextExtractor {
+        PlainTextExtractor::default()
+    }
+
+    // `label` is a WHATWG encoding label such as `gbk`, `shift_jis` or `utf-16le`
+    pub fn with_encoding(label: &str) -> Result<PlainTextExtractor, io::Error> {
+        match Encoding::for_label(label.as_bytes()) {
+            Some(encoding) => Ok(PlainTextExtractor {
+                encoding: Some(encoding),
+            }),
+            None => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("unknown encoding: {}", label),
+            )),
+        }
+    }
+}
+
+impl Extractor for PlainTextExtractor {
+    fn extract(&self, bytes: &[u8]) -> Result<ExtractedText, io::Error> {
+        let text = match self.encoding {
+            Some(encoding) => encoding.decode(bytes).0.into_owned(),
+            None => decode(bytes),
+        };
+        Ok(ExtractedText::from_plain(text))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HtmlExtractor {
+    pub options: HtmlTextOptions,
+}
+
+impl HtmlExtractor {
+    pub fn new(options: HtmlTextOptions) -> HtmlExtractor {
+        HtmlExtractor { options }
+    }
+
+    // Our rich-text format, html with `scalable_block` elements that are not text
+    pub fn xrtm() -> HtmlExtractor {
+        let mut options = HtmlTextOptions::new();
+        options.delete_tags.push("scalable_block".to_string());
+        HtmlExtractor { options }
+    }
+}
+
+impl Extractor for HtmlExtractor {
+    fn extract(&self, bytes: &[u8]) -> Result<ExtractedText, io::Error> {
+        Ok(extract_html_text(&decode(bytes), &self.options))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MarkdownExtractor {
+    pub options: MarkdownOptions,
+}
+
+impl MarkdownExtractor {
+    pub fn new(options: MarkdownOptions) -> MarkdownExtractor {
+        MarkdownExtractor { options }
+    }
+}
+
+impl Extractor for MarkdownExtractor {
+    fn extract(&self, bytes: &[u8]) -> Result<ExtractedText, io::Error> {
+        Ok(extract_markdown(&decode(bytes), &self.options))
+    }
+}
+
+// The string values of `text_keys` in a JSON document, such as the text nodes of a rich-text
+// editor state. Objects holding child nodes end a line, and the text of an object whose
+// `type` is `heading` is a heading field.
+#[derive(Debug, Clone)]
+pub struct JsonTextExtractor {
+    pub text_keys: Vec<String>,
+}
+
+impl Default for JsonTextExtractor {
+    fn default() -> Self {
+        JsonTextExtractor::new()
+    }
+}
+
+impl JsonTextExtractor {
+    pub fn new() -> JsonTextExtractor {
+        JsonTextExtractor {
+            text_keys: ["text".to_string()].to_vec(),
+        }
+    }
+
+    fn walk(&self, value: &Value, source_len: usize, res: &mut ExtractedText) {
+        match value {
+            Value::Object(map) => {
+                let start = res.text.len();
+                let mut has_children = false;
+                for (key, v) in map {
+                    match v {
+                        Value::String(s) if self.text_keys.contains(key) => {
+                            res.push_mapped(s, 0, source_len);
+                        }
+                        Value::Array(_) | Value::Object(_) => {
+                            has_children = true;
+                            self.walk(v, source_len, res);
+                        }
+                        _ => {}
+                    }
+                }
+                if map.get("type").and_then(|t| t.as_str()) == Some(FIELD_HEADING) {
+                    res.add_field(FIELD_HEADING, start);
+                }
+                if has_children {
+                    res.push_newline(source_len);
+                }
+            }
+            Value::Array(items) => {
+                for v in items {
+                    self.walk(v, source_len, res);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Extractor for JsonTextExtractor {
+    fn extract(&self, bytes: &[u8]) -> Result<ExtractedText, io::Error> {
+        let value: Value = serde_json::from_slice(bytes)?;
+        let mut res = ExtractedText::default();
+        self.walk(&value, bytes.len(), &mut res);
+        res.trim_end();
+        Ok(res)
+    }
+}
+
+// Guess the content type of a file from its first bytes
+pub fn sniff_mime(head: &[u8]) -> Option<&'static str> {
+    if head.starts_with(b"%PDF-") {
+        return Some(MIME_PDF);
+    }
+    if head.starts_with(b"PK\x03\x04") {
+        return Some(MIME_ZIP);
+    }
+
+    let text = String::from_utf8_lossy(&head[..head.len().min(512)]);
+    let text = text.trim_start_matches('\u{feff}').trim_start();
+    let lower = text.to_lowercase();
+    if lower.starts_with("<!doctype html") || lower.starts_with("<html") {
+        return Some(MIME_HTML);
+    }
+    if text.starts_with('{') || text.starts_with('[') {
+        return Some(MIME_JSON);
+    }
+    if text.starts_with("---\n") || text.starts_with("---\r\n") || text.starts_with("# ") {
+        return Some(MIME_MARKDOWN);
+    }
+
+    None
+}
+
+// The extractors of the searched files, by extension first, then by the sniffed content
+// type, then the fallback (plain text by default)
+#[derive(Clone)]
+pub struct ExtractorRegistry {
+    by_ext: BTreeMap<String, Arc<dyn Extractor>>,
+    by_mime: BTreeMap<String, Arc<dyn Extractor>>,
+    fallback: Arc<dyn Extractor>,
+    // The fallback is the default plain text extractor
+    plain_fallback: bool,
+}
+
+impl fmt::Debug for ExtractorRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExtractorRegistry")
+            .field("exts", &self.by_ext.keys().collect::<Vec<&String>>())
+            .field("mimes", &self.by_mime.keys().collect::<Vec<&String>>())
+            .finish()
+    }
+}
+
+impl Default for ExtractorRegistry {
+    fn default() -> Self {
+        ExtractorRegistry::new()
+    }
+}
+
+impl ExtractorRegistry {
+    pub fn new() -> ExtractorRegistry {
+        ExtractorRegistry {
+            by_ext: BTreeMap::new(),
+            by_mime: BTreeMap::new(),
+            fallback: Arc::new(PlainTextExtractor::new()),
+            plain_fallback: true,
+        }
+    }
+
+    // The extractors used by the search options: html for `html_like_exts` (`xrtm` without
+    // its scalable blocks) and markdown for `markdown_exts`
+    pub fn with_defaults(
+        html_like_exts: &[String],
+        markdown_exts: &[String],
+        markdown: &MarkdownOptions,
+    ) -> ExtractorRegistry {
+        let mut registry = ExtractorRegistry::new();
+        for ext in html_like_exts {
+            if ext == EXT_XRTM {
+                registry.register_ext(ext, Arc::new(HtmlExtractor::xrtm()));
+            } else {
+                registry.register_ext(ext, Arc::new(HtmlExtractor::default()));
+            }
+        }
+        for ext in markdown_exts {
+            registry.register_ext(ext, Arc::new(MarkdownExtractor::new(markdown.clone())));
+        }
+        registry
+    }
+
+    pub fn register_ext(&mut self, ext: &str, extractor: Arc<dyn Extractor>) {
+        self.by_ext
+            .insert(ext.trim_start_matches('.').to_lowercase(), extractor);
+    }
+
+    pub fn register_mime(&mut self, mime: &str, extractor: Arc<dyn Extractor>) {
+        self.by_mime.insert(mime.to_lowercase(), extractor);
+    }
+
+    pub fn set_fallback(&mut self, extractor: Arc<dyn Extractor>) {
+        self.fallback = extractor;
+        self.plain_fallback = false;
+    }
+
+    pub fn find(&self, file_path: &Path, head: &[u8]) -> Arc<dyn Extractor> {
+        let ext = file_path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("")
+            .to_lowercase();
+        if let Some(e) = self.by_ext.get(&ext) {
+            return e.clone();
+        }
+
+        if !self.by_mime.is_empty() {
+            if let Some(e) = sniff_mime(head).and_then(|m| self.by_mime.get(m)) {
+                return e.clone();
+            }
+        }
+
+        self.fallback.clone()
+    }
+
+    // Whether a file is read as plain text, which can be searched in chunks
+    pub(crate) fn is_plain_text(&self, file_path: &Path, head: &[u8]) -> bool {
+        self.plain_fallback && Arc::ptr_eq(&self.find(file_path, head), &self.fallback)
+    }
+
+    pub fn extract_bytes(
+        &self,
+        file_path: &Path,
+        bytes: &[u8],
+    ) -> Result<ExtractedText, io::Error> {
+        self.find(file_path, bytes).extract(bytes)
+    }
+
+    // A file that can not be opened has no text
+    pub fn extract_file(&self, file_path: &Path) -> Result<ExtractedText, io::Error> {
+        let bytes = match fs::read(file_path) {
+            Ok(b) => b,
+            Err(e)
+                if e.kind() == ErrorKind::NotFound || e.kind() == ErrorKind::PermissionDenied =>
+            {
+                error!("File::open error {}", e);
+                return Ok(ExtractedText::default());
+            }
+            Err(e) => return Err(e),
+        };
+        self.extract_bytes(file_path, &bytes)
+    }
+}
+
+#[test]
+fn test_extractor_registry() {
+    let mut registry = ExtractorRegistry::with_defaults(
+        &["html".to_string(), "xrtm".to_string()],
+        &["md".to_string()],
+        &MarkdownOptions::new(),
+    );
+    registry.register_mime(MIME_JSON, Arc::new(JsonTextExtractor::new()));
+
+    let extract = |name: &str, content: &str| {
+        registry
+            .extract_bytes(Path::new(name), content.as_bytes())
+            .unwrap()
+            .text
+    };
+    assert_eq!(extract("a.HTML", "<p>a &amp; b</p>"), "a & b");
+    assert_eq!(
+        extract("a.xrtm", "<p>a</p><scalable_block>x</scalable_block>"),
+        "a"
+    );
+    assert_eq!(extract("a.md", "# Title\n**b**"), "Title\nb");
+    assert_eq!(extract("a.txt", "<p>raw</p>"), "<p>raw</p>");
+
+    let state = r#"{"root": {"children": [
+        {"type": "heading", "children": [{"text": "Title", "type": "text"}]},
+        {"type": "paragraph", "children": [{"text": "gh", "type": "text"}, {"text": "f", "type": "text"}]}
+    ]}}"#;
+    let doc = registry
+        .extract_bytes(Path::new("state"), state.as_bytes())
+        .unwrap();
+    assert_eq!(doc.text, "Title\nghf");
+    assert_eq!((doc.fields[0].start, doc.fields[0].end), (0, 5));
+
+    let sjis = [
+        0x93, 0xfa, 0x96, 0x7b, 0x82, 0xcc, 0x83, 0x65, 0x83, 0x4c, 0x83, 0x58, 0x83, 0x67,
+    ];
+    assert_eq!(
+        registry
+            .extract_bytes(Path::new("a.txt"), &sjis)
+            .unwrap()
+            .text,
+        "日本のテキスト"
+    );
+
+    let gbk = PlainTextExtractor::with_encoding("gbk").unwrap();
+    assert_eq!(gbk.extract(&[0xd6, 0xd0, 0xce, 0xc4]).unwrap().text, "中文");
+    assert!(PlainTextExtractor::with_encoding("nope").is_err());
+}
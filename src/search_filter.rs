@@ -0,0 +1,307 @@
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use crate::fs::path_buf_to_string;
+use crate::fs_file;
+
+// Which files of a directory are searched
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileFilter {
+    // Only search files matching one of these globs, all files if empty
+    pub include_globs: Vec<String>,
+    // Skip files and directories matching one of these globs
+    pub exclude_globs: Vec<String>,
+    // Skip these directories, relative to the searched directory like `tree_info_vec`
+    pub exclude_dirs: Vec<String>,
+    // Honor the `.gitignore` and `.ignore` files found while walking
+    pub use_ignore_files: bool,
+    // Skip files bigger than this many bytes, 0 for no limit
+    pub max_file_size: u64,
+    pub skip_binary: bool,
+}
+
+impl Default for FileFilter {
+    fn default() -> Self {
+        FileFilter::new()
+    }
+}
+
+impl FileFilter {
+    // Skips `.git`, the ignored files and binary files, the default of `SearchOptions::new`
+    pub fn new() -> FileFilter {
+        FileFilter {
+            include_globs: [].to_vec(),
+            exclude_globs: [".git".to_string()].to_vec(),
+            exclude_dirs: [].to_vec(),
+            use_ignore_files: true,
+            max_file_size: 0,
+            skip_binary: true,
+        }
+    }
+
+    // A filter letting every file through
+    pub fn none() -> FileFilter {
+        FileFilter {
+            include_globs: [].to_vec(),
+            exclude_globs: [].to_vec(),
+            exclude_dirs: [].to_vec(),
+            use_ignore_files: false,
+            max_file_size: 0,
+            skip_binary: false,
+        }
+    }
+}
+
+fn build_glob_set(globs: &[String]) -> Result<GlobSet, io::Error> {
+    let mut builder = GlobSetBuilder::new();
+    for g in globs {
+        match Glob::new(g) {
+            Ok(glob) => {
+                builder.add(glob);
+            }
+            Err(e) => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid glob {}: {}", g, e),
+                ))
+            }
+        }
+    }
+
+    builder
+        .build()
+        .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e.to_string()))
+}
+
+// A `FileFilter` compiled for walking one directory
+pub(crate) struct DirWalker {
+    root: PathBuf,
+    filter: FileFilter,
+    include: GlobSet,
+    exclude: GlobSet,
+    exclude_dirs: Vec<PathBuf>,
+    // Extensions of the searched archives, kept even if binary, the include globs apply to the
+    // files inside them instead
+    archive_exts: Vec<String>,
+}
+
+impl DirWalker {
+    pub fn new(root: &Path, filter: &FileFilter) -> Result<DirWalker, io::Error> {
+        Ok(DirWalker {
+            root: root.to_path_buf(),
+            filter: filter.clone(),
+            include: build_glob_set(&filter.include_globs)?,
+            exclude: build_glob_set(&filter.exclude_globs)?,
+            exclude_dirs: filter.exclude_dirs.iter().map(|d| root.join(d)).collect(),
+            archive_exts: [].to_vec(),
+        })
+    }
+
+    pub fn keep_archive_exts(mut self, exts: &[String]) -> DirWalker {
+        self.archive_exts = exts.iter().map(|e| e.to_lowercase()).collect();
+        self
+    }
+
+    // Whether a file inside an archive passes the globs, `name` is its path in the archive
+    pub fn matches_entry(&self, name: &Path) -> bool {
+        if self.exclude.is_match(name) || name.iter().any(|n| self.exclude.is_match(n)) {
+            return false;
+        }
+
+        self.filter.include_globs.is_empty()
+            || self.include.is_match(name)
+            || name.file_name().is_some_and(|n| self.include.is_match(n))
+    }
+
+    // All the files to search in a stable order. Stops early when `should_stop` returns true,
+    // fails only if the root directory can not be read.
+    pub fn walk(&self, should_stop: &dyn Fn() -> bool) -> Result<Vec<PathBuf>, io::Error> {
+        let mut files: Vec<PathBuf> = Vec::new();
+        self.walk_dir(&self.root, &[], should_stop, &mut files)?;
+        Ok(files)
+    }
+
+    fn walk_dir(
+        &self,
+        dir_path: &Path,
+        parent_ignores: &[Gitignore],
+        should_stop: &dyn Fn() -> bool,
+        files: &mut Vec<PathBuf>,
+    ) -> Result<(), io::Error> {
+        let mut entries: Vec<PathBuf> = fs::read_dir(dir_path)?
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .collect();
+        entries.sort();
+
+        let mut ignores = parent_ignores.to_vec();
+        if self.filter.use_ignore_files {
+            if let Some(gi) = load_ignore_files(dir_path) {
+                ignores.push(gi);
+            }
+        }
+
+        for path in entries {
+            if should_stop() {
+                break;
+            }
+
+            if path.is_dir() {
+                if self.skip_dir(&path, &ignores) {
+                    continue;
+                }
+                if let Err(e) = self.walk_dir(&path, &ignores, should_stop, files) {
+                    debug!("walk dir error: {}", e);
+                }
+            } else if path.is_file() && !self.skip_file(&path, &ignores) {
+                files.push(path);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn matches_exclude(&self, path: &Path) -> bool {
+        let rel = path.strip_prefix(&self.root).unwrap_or(path);
+        self.exclude.is_match(rel) || path.file_name().is_some_and(|n| self.exclude.is_match(n))
+    }
+
+    fn skip_dir(&self, path: &Path, ignores: &[Gitignore]) -> bool {
+        self.exclude_dirs.iter().any(|d| d == path)
+            || self.matches_exclude(path)
+            || is_ignored(ignores, path, true)
+    }
+
+    fn skip_file(&self, path: &Path, ignores: &[Gitignore]) -> bool {
+        if self.matches_exclude(path) || is_ignored(ignores, path, false) {
+            return true;
+        }
+
+        let ext = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("")
+            .to_lowercase();
+        let is_archive = self.archive_exts.contains(&ext);
+        if !is_archive && !self.filter.include_globs.is_empty() {
+            let rel = path.strip_prefix(&self.root).unwrap_or(path);
+            let included = self.include.is_match(rel)
+                || path.file_name().is_some_and(|n| self.include.is_match(n));
+            if !included {
+                return true;
+            }
+        }
+
+        let path_str = path_buf_to_string(path.to_path_buf());
+        if self.filter.max_file_size > 0
+            && fs_file::get_file_size(&path_str) > self.filter.max_file_size
+        {
+            return true;
+        }
+
+        self.filter.skip_binary && !is_archive && fs_file::is_binary(&path_str)
+    }
+}
+
+// The `.gitignore` and `.ignore` rules of a directory, `.ignore` wins on conflicts
+fn load_ignore_files(dir_path: &Path) -> Option<Gitignore> {
+    let mut builder = GitignoreBuilder::new(dir_path);
+    let mut found = false;
+    for name in [".gitignore", ".ignore"] {
+        let p = dir_path.join(name);
+        if p.is_file() {
+            found = true;
+            if let Some(e) = builder.add(p) {
+                debug!("load ignore file error: {}", e);
+            }
+        }
+    }
+    if !found {
+        return None;
+    }
+
+    match builder.build() {
+        Ok(gi) => Some(gi),
+        Err(e) => {
+            debug!("build ignore rules error: {}", e);
+            None
+        }
+    }
+}
+
+// The deepest ignore file with a matching rule decides
+fn is_ignored(ignores: &[Gitignore], path: &Path, is_dir: bool) -> bool {
+    for gi in ignores.iter().rev() {
+        let m = gi.matched(path, is_dir);
+        if m.is_ignore() {
+            return true;
+        }
+        if m.is_whitelist() {
+            return false;
+        }
+    }
+
+    false
+}
+
+#[test]
+fn test_dir_walker() {
+    let dir = std::env::temp_dir().join("fivim_rs_utils_test_search_filter");
+    let _ = std::fs::remove_dir_all(&dir);
+    let write = |name: &str, content: &str| {
+        let _ = fs_file::write_str(&path_buf_to_string(dir.join(name)), content);
+    };
+    write("a.md", "text");
+    write("b.txt", "text");
+    write("big.md", &"x".repeat(2000));
+    write("image.png", "\u{0}\u{1}binary");
+    write(".gitignore", "build/\n*.log\n");
+    write("build/out.md", "text");
+    write("app.log", "text");
+    write(".git/HEAD", "ref");
+    write("sub/.ignore", "!keep.log\n");
+    write("sub/keep.log", "text");
+    write("sub/c.md", "text");
+    write("attachments/d.md", "text");
+
+    let names = |filter: &FileFilter| -> Vec<String> {
+        DirWalker::new(&dir, filter)
+            .unwrap()
+            .walk(&|| false)
+            .unwrap()
+            .iter()
+            .map(|p| {
+                path_buf_to_string(p.strip_prefix(&dir).unwrap().to_path_buf()).replace('\\', "/")
+            })
+            .collect()
+    };
+
+    let mut filter = FileFilter::new();
+    filter.max_file_size = 1000;
+    filter.exclude_dirs = ["attachments".to_string()].to_vec();
+    assert_eq!(
+        names(&filter),
+        [
+            ".gitignore",
+            "a.md",
+            "b.txt",
+            "sub/.ignore",
+            "sub/c.md",
+            "sub/keep.log"
+        ]
+    );
+
+    filter.include_globs = ["*.md".to_string()].to_vec();
+    assert_eq!(names(&filter), ["a.md", "sub/c.md"]);
+
+    assert_eq!(names(&FileFilter::none()).len(), 12);
+
+    filter.include_globs = ["[".to_string()].to_vec();
+    assert!(DirWalker::new(&dir, &filter).is_err());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
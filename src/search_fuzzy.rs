@@ -0,0 +1,149 @@
+use crate::search::wrap_text;
+use crate::search_tokenizer::{CjkTokenizer, Tokenizer};
+
+// A range of the text matching the search within the edit distance
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuzzySpan {
+    pub start: usize,
+    pub end: usize,
+    pub distance: usize,
+    // 1.0 for an exact match, lower for near misses
+    pub score: f32,
+}
+
+// Levenshtein distance of two strings in characters, stops counting once `max` is exceeded
+pub fn levenshtein(a: &str, b: &str, max: usize) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    if a.len().abs_diff(b.len()) > max {
+        return max + 1;
+    }
+
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut cur: Vec<usize> = vec![0; b.len() + 1];
+    for i in 1..=a.len() {
+        cur[0] = i;
+        let mut row_min = cur[0];
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            cur[j] = (prev[j] + 1).min(cur[j - 1] + 1).min(prev[j - 1] + cost);
+            row_min = row_min.min(cur[j]);
+        }
+        if row_min > max {
+            return max + 1;
+        }
+        std::mem::swap(&mut prev, &mut cur);
+    }
+
+    prev[b.len()]
+}
+
+// Find the consecutive words of `string` whose total edit distance to the words of `search`
+// is at most `max_distance`. Words are compared case insensitively, and a word never matches
+// with a distance equal or greater than its own length, so short words are not matched by
+// everything.
+pub fn find_fuzzy_spans(string: &str, search: &str, max_distance: usize) -> Vec<FuzzySpan> {
+    let tokenizer = CjkTokenizer::new();
+    let query: Vec<String> = tokenizer
+        .tokenize(search)
+        .into_iter()
+        .map(|t| t.text)
+        .collect();
+    if query.is_empty() {
+        return [].to_vec();
+    }
+    let query_chars: usize = query.iter().map(|q| q.chars().count()).sum();
+
+    let tokens = tokenizer.tokenize(string);
+    let mut spans: Vec<FuzzySpan> = Vec::new();
+    let mut i = 0;
+    while i + query.len() <= tokens.len() {
+        let window = &tokens[i..i + query.len()];
+        let mut distance = 0;
+        for (t, q) in window.iter().zip(query.iter()) {
+            let d = levenshtein(&t.text, q, max_distance - distance.min(max_distance));
+            if d >= q.chars().count() {
+                distance = max_distance + 1;
+            } else {
+                distance += d;
+            }
+            if distance > max_distance {
+                break;
+            }
+        }
+
+        if distance <= max_distance {
+            spans.push(FuzzySpan {
+                start: window[0].start,
+                end: window[window.len() - 1].end,
+                distance,
+                score: 1.0 - distance as f32 / query_chars.max(1) as f32,
+            });
+            i += query.len();
+        } else {
+            i += 1;
+        }
+    }
+
+    spans
+}
+
+// Fuzzy version of `find_matches`, also returns the best score of the matches
+pub fn find_matches_fuzzy(
+    string: &str,
+    search: &str,
+    max_distance: usize,
+    context_size: usize,
+    prefix: &str,
+    postfix: &str,
+) -> (Vec<String>, f32) {
+    let spans = find_fuzzy_spans(string, search, max_distance);
+    let best = spans.iter().fold(0.0_f32, |acc, s| acc.max(s.score));
+
+    let matches = spans
+        .into_iter()
+        .map(|s| {
+            wrap_text(
+                string,
+                &string[s.start..s.end],
+                s.start,
+                s.end,
+                context_size,
+                prefix,
+                postfix,
+            )
+            .0
+        })
+        .collect();
+
+    (matches, best)
+}
+
+#[test]
+fn test_levenshtein() {
+    assert_eq!(levenshtein("kitten", "sitting", 5), 3);
+    assert_eq!(levenshtein("kitten", "sitting", 2), 3);
+    assert_eq!(levenshtein("中文", "中文", 2), 0);
+    assert_eq!(levenshtein("", "abc", 5), 3);
+}
+
+#[test]
+fn test_find_matches_fuzzy() {
+    let string = "Asynchronous programming in Rust, async erorr handling";
+
+    let spans = find_fuzzy_spans(string, "asynchronus", 2);
+    assert_eq!(spans.len(), 1);
+    assert_eq!(&string[spans[0].start..spans[0].end], "Asynchronous");
+    assert_eq!(spans[0].distance, 1);
+
+    let (matches, best) = find_matches_fuzzy(string, "error handling", 2, 4, "<b>", "</b>");
+    assert_eq!(matches, ["ync <b>erorr handling</b>"]);
+    assert!(best < 1.0 && best > 0.8);
+
+    let (_, best) = find_matches_fuzzy(string, "rust", 2, 4, "<b>", "</b>");
+    assert_eq!(best, 1.0);
+
+    assert!(find_fuzzy_spans(string, "in", 2)
+        .iter()
+        .all(|s| s.distance == 0));
+}
@@ -0,0 +1,515 @@
+use html_escape::decode_html_entities;
+use serde::{Deserialize, Serialize};
+
+const VOID_TAGS: [&str; 14] = [
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source",
+    "track", "wbr",
+];
+
+// Elements whose content is not parsed as html, the content of `script` and `style` is
+// never text
+const RAW_TEXT_TAGS: [&str; 4] = ["script", "style", "textarea", "title"];
+const NON_TEXT_TAGS: [&str; 2] = ["script", "style"];
+const HEADING_TAGS: [&str; 6] = ["h1", "h2", "h3", "h4", "h5", "h6"];
+
+// Name of the fields of the headings, for html and markdown
+pub const FIELD_HEADING: &str = "heading";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HtmlTextOptions {
+    // Elements removed with their content
+    pub delete_tags: Vec<String>,
+    // Elements starting and ending a line of text
+    pub block_tags: Vec<String>,
+}
+
+impl Default for HtmlTextOptions {
+    fn default() -> Self {
+        HtmlTextOptions::new()
+    }
+}
+
+impl HtmlTextOptions {
+    pub fn new() -> HtmlTextOptions {
+        let to_vec = |tags: &[&str]| tags.iter().map(|t| t.to_string()).collect::<Vec<String>>();
+        HtmlTextOptions {
+            delete_tags: to_vec(&["head", "script", "style", "template", "noscript"]),
+            block_tags: to_vec(&[
+                "address",
+                "article",
+                "aside",
+                "blockquote",
+                "br",
+                "dd",
+                "div",
+                "dl",
+                "dt",
+                "figcaption",
+                "figure",
+                "footer",
+                "form",
+                "h1",
+                "h2",
+                "h3",
+                "h4",
+                "h5",
+                "h6",
+                "header",
+                "hr",
+                "li",
+                "main",
+                "nav",
+                "ol",
+                "p",
+                "pre",
+                "section",
+                "table",
+                "td",
+                "th",
+                "tr",
+                "ul",
+            ]),
+        }
+    }
+
+    pub fn with_delete_tags(delete_tags: &[String]) -> HtmlTextOptions {
+        let mut options = HtmlTextOptions::new();
+        options.delete_tags = delete_tags.iter().map(|t| t.to_lowercase()).collect();
+        options
+    }
+}
+
+// A piece of the extracted text and where it comes from
+#[derive(Debug, Clone, PartialEq)]
+struct Segment {
+    text_start: usize,
+    source_start: usize,
+    source_end: usize,
+    // Copied byte for byte from the source, otherwise a decoded entity or a generated
+    // separator mapping to its whole source range
+    is_literal: bool,
+}
+
+// A named range of the extracted text, such as a heading, that queries can target
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TextField {
+    pub name: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+// The text of a document, with the mapping of its offsets to the source
+#[derive(Debug, Clone, Default)]
+pub struct ExtractedText {
+    pub text: String,
+    pub fields: Vec<TextField>,
+    segments: Vec<Segment>,
+}
+
+impl ExtractedText {
+    // A text that is its own source
+    pub fn from_plain(text: String) -> ExtractedText {
+        let mut res = ExtractedText::default();
+        res.segments.push(Segment {
+            text_start: 0,
+            source_start: 0,
+            source_end: text.len(),
+            is_literal: true,
+        });
+        res.text = text;
+        res
+    }
+
+    // The byte offset in the source of the byte `text_offset` of the text
+    pub fn source_offset(&self, text_offset: usize) -> usize {
+        let i = self
+            .segments
+            .partition_point(|s| s.text_start <= text_offset);
+        if i == 0 {
+            return 0;
+        }
+
+        let seg = &self.segments[i - 1];
+        if seg.is_literal {
+            (seg.source_start + text_offset - seg.text_start).min(seg.source_end)
+        } else {
+            seg.source_start
+        }
+    }
+
+    // The source range of the text range `start..end`, covering whole entities
+    pub fn source_span(&self, start: usize, end: usize) -> (usize, usize) {
+        if end <= start {
+            let s = self.source_offset(start);
+            return (s, s);
+        }
+
+        let last = end - 1;
+        let i = self.segments.partition_point(|s| s.text_start <= last);
+        let source_end = match i {
+            0 => 0,
+            _ => {
+                let seg = &self.segments[i - 1];
+                if seg.is_literal {
+                    (seg.source_start + end - seg.text_start).min(seg.source_end)
+                } else {
+                    seg.source_end
+                }
+            }
+        };
+
+        (self.source_offset(start), source_end)
+    }
+
+    pub(crate) fn push_literal(&mut self, s: &str, source_start: usize) {
+        if s.is_empty() {
+            return;
+        }
+        match self.segments.last_mut() {
+            Some(last) if last.is_literal && last.source_end == source_start => {
+                last.source_end += s.len();
+            }
+            _ => self.segments.push(Segment {
+                text_start: self.text.len(),
+                source_start,
+                source_end: source_start + s.len(),
+                is_literal: true,
+            }),
+        }
+        self.text.push_str(s);
+    }
+
+    pub(crate) fn push_mapped(&mut self, s: &str, source_start: usize, source_end: usize) {
+        self.segments.push(Segment {
+            text_start: self.text.len(),
+            source_start,
+            source_end,
+            is_literal: false,
+        });
+        self.text.push_str(s);
+    }
+
+    fn ends_with_space(&self) -> bool {
+        self.text.is_empty() || self.text.ends_with([' ', '\n'])
+    }
+
+    pub(crate) fn push_space(&mut self, source_pos: usize) {
+        if !self.ends_with_space() {
+            self.push_mapped(" ", source_pos, source_pos);
+        }
+    }
+
+    pub(crate) fn push_newline(&mut self, source_pos: usize) {
+        if self.text.is_empty() || self.text.ends_with('\n') {
+            return;
+        }
+        if self.text.ends_with(' ') {
+            self.text.pop();
+            if self
+                .segments
+                .last()
+                .is_some_and(|s| s.text_start == self.text.len())
+            {
+                self.segments.pop();
+            } else if let Some(last) = self.segments.last_mut() {
+                if last.is_literal {
+                    last.source_end -= 1;
+                }
+            }
+        }
+        self.push_mapped("\n", source_pos, source_pos);
+    }
+
+    pub(crate) fn trim_end(&mut self) {
+        let len = self.text.trim_end().len();
+        self.text.truncate(len);
+        self.segments.retain(|s| s.text_start < len);
+        for f in self.fields.iter_mut() {
+            f.end = f.end.min(len);
+        }
+        self.fields.retain(|f| f.start < f.end);
+    }
+
+    // Name the text pushed since `start`, trailing whitespace excluded
+    pub(crate) fn add_field(&mut self, name: &str, start: usize) {
+        let end = start + self.text[start..].trim_end().len();
+        if end > start {
+            self.fields.push(TextField {
+                name: name.to_string(),
+                start,
+                end,
+            });
+        }
+    }
+}
+
+pub(crate) struct Tag {
+    pub name: String,
+    pub is_end: bool,
+    pub is_self_closing: bool,
+    // False if the document ended before the closing `>`
+    pub is_closed: bool,
+    pub end: usize,
+}
+
+fn is_name_char(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b':'
+}
+
+// Parse the tag starting at the `<` at `start`, quoted attribute values may contain `>`
+pub(crate) fn parse_tag(html: &str, start: usize) -> Option<Tag> {
+    let bytes = html.as_bytes();
+    let mut i = start + 1;
+    let is_end = bytes.get(i) == Some(&b'/');
+    if is_end {
+        i += 1;
+    }
+    if !bytes.get(i).is_some_and(|b| b.is_ascii_alphabetic()) {
+        return None;
+    }
+
+    let name_start = i;
+    while i < bytes.len() && is_name_char(bytes[i]) {
+        i += 1;
+    }
+    let name = html[name_start..i].to_ascii_lowercase();
+
+    let mut quote: Option<u8> = None;
+    while i < bytes.len() {
+        let b = bytes[i];
+        match quote {
+            Some(q) if b == q => quote = None,
+            Some(_) => {}
+            None if b == b'"' || b == b'\'' => quote = Some(b),
+            None if b == b'>' => {
+                return Some(Tag {
+                    name,
+                    is_end,
+                    is_self_closing: bytes[i - 1] == b'/',
+                    is_closed: true,
+                    end: i + 1,
+                })
+            }
+            None => {}
+        }
+        i += 1;
+    }
+
+    // Unclosed tag, it runs to the end of the document
+    Some(Tag {
+        name,
+        is_end,
+        is_self_closing: false,
+        is_closed: false,
+        end: bytes.len(),
+    })
+}
+
+// Byte offset of the end tag `</name` at or after `from`, case insensitive
+fn find_end_tag(html: &str, from: usize, name: &str) -> Option<usize> {
+    let bytes = html.as_bytes();
+    let mut i = from;
+    while let Some(p) = html[i..].find("</") {
+        let start = i + p;
+        let name_end = start + 2 + name.len();
+        if name_end <= bytes.len()
+            && bytes[start + 2..name_end].eq_ignore_ascii_case(name.as_bytes())
+            && !bytes.get(name_end).is_some_and(|b| is_name_char(*b))
+        {
+            return Some(start);
+        }
+        i = start + 2;
+    }
+    None
+}
+
+// Decode the entity at the `&` at `start`, returns the decoded text and its source end
+pub(crate) fn decode_entity(html: &str, start: usize) -> Option<(String, usize)> {
+    let rest = &html[start..];
+    let semi = rest.char_indices().take(33).find(|(_, c)| *c == ';')?.0;
+    let entity = &rest[..=semi];
+    let decoded = decode_html_entities(entity);
+    if decoded == entity {
+        return None;
+    }
+    Some((decoded.to_string(), start + semi + 1))
+}
+
+// Extract the text of an html document with a tolerant tokenizer. The elements in
+// `options.delete_tags` are removed with their content, block elements are separated by
+// newlines, other whitespace is collapsed to single spaces (except inside `pre`) and
+// entities are decoded.
+pub fn extract_html_text(html: &str, options: &HtmlTextOptions) -> ExtractedText {
+    let mut res = ExtractedText::default();
+    let is_block = |name: &str| options.block_tags.iter().any(|t| t == name);
+    let is_deleted = |name: &str| options.delete_tags.iter().any(|t| t == name);
+
+    let is_heading = |name: &str| HEADING_TAGS.contains(&name);
+
+    let bytes = html.as_bytes();
+    let mut pre_depth = 0;
+    let mut heading_start: Option<usize> = None;
+    let mut i = 0;
+    while i < bytes.len() {
+        let b = bytes[i];
+
+        if b == b'<' {
+            if html[i..].starts_with("<!--") {
+                i = html[i + 4..]
+                    .find("-->")
+                    .map_or(bytes.len(), |p| i + 4 + p + 3);
+                continue;
+            }
+            if html[i..].starts_with("<!") || html[i..].starts_with("<?") {
+                i = html[i..].find('>').map_or(bytes.len(), |p| i + p + 1);
+                continue;
+            }
+
+            if let Some(tag) = parse_tag(html, i) {
+                let tag_start = i;
+                i = tag.end;
+                if tag.is_end {
+                    if tag.name == "pre" && pre_depth > 0 {
+                        pre_depth -= 1;
+                    }
+                    if is_heading(&tag.name) {
+                        if let Some(start) = heading_start.take() {
+                            res.add_field(FIELD_HEADING, start);
+                        }
+                    }
+                } else if is_deleted(&tag.name)
+                    && !tag.is_self_closing
+                    && !VOID_TAGS.contains(&tag.name.as_str())
+                {
+                    i = skip_element(html, i, &tag.name);
+                    res.push_space(tag_start);
+                    continue;
+                } else if RAW_TEXT_TAGS.contains(&tag.name.as_str()) && !tag.is_self_closing {
+                    let end = find_end_tag(html, i, &tag.name).unwrap_or(bytes.len());
+                    if !NON_TEXT_TAGS.contains(&tag.name.as_str()) {
+                        push_text(&mut res, html, i, end, false);
+                    }
+                    i = end;
+                } else if tag.name == "pre" && !tag.is_self_closing {
+                    pre_depth += 1;
+                }
+
+                if is_block(&tag.name) {
+                    res.push_newline(tag_start);
+                }
+                if !tag.is_end && is_heading(&tag.name) {
+                    heading_start = Some(res.text.len());
+                }
+                continue;
+            }
+        }
+
+        // A `<` not starting a tag is text
+        let from = if b == b'<' { i + 1 } else { i };
+        let next = html[from..].find('<').map_or(bytes.len(), |p| from + p);
+        push_text(&mut res, html, i, next, pre_depth > 0);
+        i = next;
+    }
+
+    res.trim_end();
+    res
+}
+
+// The end of the element whose start tag ends at `from`, nested elements of the same name
+// included. `head` also ends at `<body`, which is where browsers close it when its end tag
+// is missing.
+fn skip_element(html: &str, from: usize, name: &str) -> usize {
+    let mut depth = 1;
+    let mut i = from;
+    while let Some(p) = html[i..].find('<') {
+        let start = i + p;
+        match parse_tag(html, start) {
+            Some(tag) if tag.name == name => {
+                i = tag.end;
+                if tag.is_end {
+                    depth -= 1;
+                    if depth == 0 {
+                        return i;
+                    }
+                } else if !tag.is_self_closing {
+                    depth += 1;
+                }
+            }
+            Some(tag) if name == "head" && tag.name == "body" && !tag.is_end => return start,
+            Some(tag) if RAW_TEXT_TAGS.contains(&tag.name.as_str()) && !tag.is_end => {
+                i = find_end_tag(html, tag.end, &tag.name).unwrap_or(html.len());
+            }
+            _ => i = start + 1,
+        }
+    }
+
+    html.len()
+}
+
+fn push_text(res: &mut ExtractedText, html: &str, start: usize, end: usize, keep_space: bool) {
+    let mut run_start = start;
+    let mut i = start;
+    let bytes = html.as_bytes();
+    while i < end {
+        let b = bytes[i];
+        if b == b'&' {
+            if let Some((decoded, entity_end)) = decode_entity(&html[..end], i) {
+                res.push_literal(&html[run_start..i], run_start);
+                res.push_mapped(&decoded, i, entity_end);
+                i = entity_end;
+                run_start = i;
+                continue;
+            }
+        } else if b.is_ascii_whitespace() && !keep_space {
+            res.push_literal(&html[run_start..i], run_start);
+            let ws_start = i;
+            while i < end && bytes[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            res.push_space(ws_start);
+            run_start = i;
+            continue;
+        }
+        i += 1;
+    }
+    res.push_literal(&html[run_start..end], run_start);
+}
+
+#[test]
+fn test_extract_html_text() {
+    let html = "<html><HEAD>\n<title>T</title>\n</HEAD><body>\n<p>Fish &amp;\n  chips</p>\
+        <script>if (a < b) {}</script><div\n class=\"x > y\">Tom<br/>Jerry</div>\
+        <!-- <p>comment</p> --><pre>a  b</pre><scalable_block><p>x</p></scalable_block>tail";
+
+    let options = HtmlTextOptions::new();
+    let res = extract_html_text(html, &options);
+    assert_eq!(res.text, "Fish & chips\nTom\nJerry\na  b\nx\ntail");
+
+    let start = res.text.find("chips").unwrap();
+    let (s, e) = res.source_span(start, start + 5);
+    assert_eq!(&html[s..e], "chips");
+    let res_h = extract_html_text("<p>a</p><h2>Title <i>x</i></h2>b", &options);
+    assert_eq!(res_h.text, "a\nTitle x\nb");
+    assert_eq!(
+        res_h.fields,
+        [TextField {
+            name: FIELD_HEADING.to_string(),
+            start: 2,
+            end: 9
+        }]
+    );
+    let amp = res.text.find('&').unwrap();
+    assert_eq!(
+        res.source_span(amp, amp + 1),
+        (html.find("&amp;").unwrap(), html.find("&amp;").unwrap() + 5)
+    );
+
+    let options =
+        HtmlTextOptions::with_delete_tags(&["head".to_string(), "scalable_block".to_string()]);
+    let res = extract_html_text(html, &options);
+    assert!(res.text.ends_with("a  b\ntail"));
+    assert!(!res.text.contains("if (a"));
+
+    let res = extract_html_text("<title>x</aé中</title> body", &HtmlTextOptions::new());
+    assert_eq!(res.text, "x</aé中 body");
+}
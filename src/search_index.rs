@@ -0,0 +1,513 @@
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::fs::path_buf_to_string;
+use crate::fs_file;
+use crate::search::{
+    compile_search, file_name_matches, search_file_content, CjkTokenizer, ExtractorRegistry,
+    MarkdownOptions, Normalization, SearchFileRes, SearchMode, SearchOptions, Tokenizer,
+};
+use crate::search_filter::{DirWalker, FileFilter};
+use crate::search_rank::{rank_results, CorpusStats};
+use crate::search_tokenizer::is_cjk;
+
+const INDEX_VERSION: u32 = 3;
+
+// Size and modified time of a file when it was tokenized, used to detect changes
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileFingerprint {
+    // Nanoseconds since UNIX_EPOCH
+    pub modified: u64,
+    pub size: u64,
+}
+
+impl FileFingerprint {
+    pub fn of(file_path: &str) -> FileFingerprint {
+        let modified = match fs_file::get_modified(file_path).duration_since(SystemTime::UNIX_EPOCH)
+        {
+            Ok(d) => d.as_nanos() as u64,
+            Err(_) => 0,
+        };
+
+        FileFingerprint {
+            modified,
+            size: fs_file::get_file_size(file_path),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IndexedFile {
+    pub fingerprint: FileFingerprint,
+    pub token_count: u32,
+    // Distinct terms of the file, so the postings can be cleaned when it changes
+    terms: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct IndexUpdateStats {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+    pub unchanged: usize,
+}
+
+// An inverted index of a notes directory: term -> file path -> term frequency.
+// The index is only used to narrow down the files to scan, the matches are still
+// produced by `find_matches`, so the results are the same as `search_in_dir`.
+// The files are read through the extractors built from the settings kept in the index and
+// split into terms by the tokenizer of the options, whose name is kept in the index.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SearchIndex {
+    version: u32,
+    html_like_exts: Vec<String>,
+    markdown_exts: Vec<String>,
+    markdown: MarkdownOptions,
+    tokenizer_name: String,
+    #[serde(skip, default = "default_tokenizer")]
+    tokenizer: Arc<dyn Tokenizer>,
+    files: BTreeMap<String, IndexedFile>,
+    postings: BTreeMap<String, BTreeMap<String, u32>>,
+}
+
+fn default_tokenizer() -> Arc<dyn Tokenizer> {
+    Arc::new(CjkTokenizer::new())
+}
+
+impl SearchIndex {
+    // An empty index with the extractor settings and the tokenizer of `options`
+    pub fn new(options: &SearchOptions) -> SearchIndex {
+        let tokenizer = options.tokenizer();
+        SearchIndex {
+            version: INDEX_VERSION,
+            html_like_exts: options.html_like_exts.clone(),
+            markdown_exts: options.markdown_exts.clone(),
+            markdown: options.markdown.clone(),
+            tokenizer_name: tokenizer.name(),
+            tokenizer,
+            files: BTreeMap::new(),
+            postings: BTreeMap::new(),
+        }
+    }
+
+    // Load the index file, a missing, outdated or incompatible index, or one built with other
+    // extractor settings or another tokenizer than `options`, is replaced by an empty one
+    pub fn open(index_path: &str, options: &SearchOptions) -> SearchIndex {
+        let content = match fs_file::read_to_string(index_path) {
+            Ok(c) => c,
+            Err(e) => {
+                debug!("read search index error: {}", e);
+                return SearchIndex::new(options);
+            }
+        };
+        if content.is_empty() {
+            return SearchIndex::new(options);
+        }
+
+        match serde_json::from_str::<SearchIndex>(&content) {
+            Ok(mut index) if index.version == INDEX_VERSION && index.has_extractors_of(options) => {
+                index.tokenizer = options.tokenizer();
+                index
+            }
+            Ok(_) => SearchIndex::new(options),
+            Err(e) => {
+                debug!("parse search index error: {}", e);
+                SearchIndex::new(options)
+            }
+        }
+    }
+
+    fn has_extractors_of(&self, options: &SearchOptions) -> bool {
+        self.html_like_exts == options.html_like_exts
+            && self.markdown_exts == options.markdown_exts
+            && self.markdown == options.markdown
+            && self.tokenizer_name == options.tokenizer().name()
+    }
+
+    fn extractor_registry(&self) -> ExtractorRegistry {
+        ExtractorRegistry::with_defaults(&self.html_like_exts, &self.markdown_exts, &self.markdown)
+    }
+
+    pub fn save(&self, index_path: &str) -> Result<(), Box<dyn Error>> {
+        let content = serde_json::to_string(self)?;
+        fs_file::write_str(index_path, &content)
+    }
+
+    pub fn file_count(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn term_count(&self) -> usize {
+        self.postings.len()
+    }
+
+    pub fn get_file(&self, file_path: &str) -> Option<&IndexedFile> {
+        self.files.get(file_path)
+    }
+
+    // Re-tokenize the files whose fingerprint changed and drop the deleted ones, or the ones
+    // no longer passing `filter`.
+    // `skip_path` is usually the index file itself when it lives inside `dir_path`.
+    pub fn update(
+        &mut self,
+        dir_path: &Path,
+        filter: &FileFilter,
+        skip_path: Option<&Path>,
+    ) -> Result<IndexUpdateStats, io::Error> {
+        let mut stats = IndexUpdateStats::default();
+        let mut seen: BTreeSet<String> = BTreeSet::new();
+
+        let registry = self.extractor_registry();
+        let files = DirWalker::new(dir_path, filter)?.walk(&|| false)?;
+        for file_path in files {
+            if skip_path.is_some_and(|p| p == file_path) {
+                continue;
+            }
+
+            let path = path_buf_to_string(file_path.clone());
+            let fingerprint = FileFingerprint::of(&path);
+            seen.insert(path.clone());
+
+            let is_new = match self.files.get(&path) {
+                Some(f) if f.fingerprint == fingerprint => {
+                    stats.unchanged += 1;
+                    continue;
+                }
+                Some(_) => false,
+                None => true,
+            };
+
+            let text = match registry.extract_file(&file_path) {
+                Ok(t) => t.text,
+                Err(e) => {
+                    debug!("index file error: {}", e);
+                    String::new()
+                }
+            };
+            self.add_file(&path, &text, fingerprint);
+
+            if is_new {
+                stats.added += 1;
+            } else {
+                stats.updated += 1;
+            }
+        }
+
+        let deleted: Vec<String> = self
+            .files
+            .keys()
+            .filter(|p| !seen.contains(*p))
+            .cloned()
+            .collect();
+        for path in deleted {
+            self.remove_file(&path);
+            stats.removed += 1;
+        }
+
+        Ok(stats)
+    }
+
+    pub fn add_file(&mut self, file_path: &str, text: &str, fingerprint: FileFingerprint) {
+        self.remove_file(file_path);
+
+        let tokens = self.tokenizer.tokenize(text);
+        let mut frequencies: BTreeMap<String, u32> = BTreeMap::new();
+        for token in &tokens {
+            *frequencies.entry(token.text.clone()).or_insert(0) += 1;
+        }
+
+        for (term, tf) in &frequencies {
+            self.postings
+                .entry(term.clone())
+                .or_default()
+                .insert(file_path.to_string(), *tf);
+        }
+
+        self.files.insert(
+            file_path.to_string(),
+            IndexedFile {
+                fingerprint,
+                token_count: tokens.len() as u32,
+                terms: frequencies.into_keys().collect(),
+            },
+        );
+    }
+
+    pub fn remove_file(&mut self, file_path: &str) {
+        let file = match self.files.remove(file_path) {
+            Some(f) => f,
+            None => return,
+        };
+
+        for term in file.terms {
+            if let Some(list) = self.postings.get_mut(&term) {
+                list.remove(file_path);
+                if list.is_empty() {
+                    self.postings.remove(&term);
+                }
+            }
+        }
+    }
+
+    // Files that may contain `search` as a plain substring.
+    // Every token of the search must be a part of some term of the file, because the
+    // occurrence of a token always lies inside one token of the file. Words found in a
+    // dictionary may be cut differently in the search and in the file, so their characters are
+    // looked for one by one.
+    pub fn candidates(&self, search: &str) -> Vec<String> {
+        let mut query_tokens: BTreeSet<String> = BTreeSet::new();
+        for token in self.tokenizer.tokenize(search) {
+            if !self.tokenizer.is_ngram() && token.text.chars().any(is_cjk) {
+                query_tokens.extend(token.text.chars().map(|c| c.to_string()));
+            } else {
+                query_tokens.insert(token.text);
+            }
+        }
+
+        self.files_with_terms(&query_tokens, &|term, qt| term.contains(qt))
+    }
+
+    // Files that may contain `search` in the word mode: every token of the search is a term of
+    // the file, or a part of one for a single character searched in n-grams
+    pub fn word_candidates(&self, search: &str) -> Vec<String> {
+        let query_tokens: BTreeSet<String> = self
+            .tokenizer
+            .tokenize(search)
+            .into_iter()
+            .map(|t| t.text)
+            .collect();
+        let is_ngram = self.tokenizer.is_ngram();
+
+        self.files_with_terms(&query_tokens, &|term, qt| {
+            let mut chars = qt.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) if is_ngram && is_cjk(c) => term.contains(qt),
+                _ => term == qt,
+            }
+        })
+    }
+
+    // Files having, for each query token, a term matching it
+    fn files_with_terms(
+        &self,
+        query_tokens: &BTreeSet<String>,
+        matches: &dyn Fn(&str, &str) -> bool,
+    ) -> Vec<String> {
+        if query_tokens.is_empty() {
+            return self.files.keys().cloned().collect();
+        }
+
+        let mut res: Option<BTreeSet<String>> = None;
+        for qt in query_tokens {
+            let mut files: BTreeSet<String> = BTreeSet::new();
+            for (term, list) in &self.postings {
+                if matches(term, qt) {
+                    files.extend(list.keys().cloned());
+                }
+            }
+
+            res = Some(match res {
+                Some(r) => r.intersection(&files).cloned().collect(),
+                None => files,
+            });
+            if res.as_ref().is_some_and(|r| r.is_empty()) {
+                break;
+            }
+        }
+
+        res.unwrap_or_default().into_iter().collect()
+    }
+
+    // Same as `search_in_dir_with_options`, but only the candidate files are scanned.
+    // Regex and queries can not be checked against the terms, so all indexed files are scanned
+    // in these modes, the same for accent or Unicode normalization folding.
+    // The extractor settings and the tokenizer of `options` are ignored, the files are read
+    // like when they were indexed.
+    pub fn search(
+        &self,
+        search: &str,
+        options: &SearchOptions,
+    ) -> Result<Vec<SearchFileRes>, io::Error> {
+        let mut options = options.clone();
+        options.html_like_exts = self.html_like_exts.clone();
+        options.markdown_exts = self.markdown_exts.clone();
+        options.markdown = self.markdown.clone();
+        options.extractors = None;
+        options.tokenizer = Some(self.tokenizer.clone());
+        let compiled = compile_search(search, &options)?;
+
+        let plain_match = &options.plain_match;
+        let paths: Vec<String> = match options.mode {
+            SearchMode::Plain
+                if !plain_match.ignore_accents
+                    && plain_match.normalization == Normalization::None =>
+            {
+                self.candidates(search)
+            }
+            SearchMode::Word => self.word_candidates(search),
+            _ => self.files.keys().cloned().collect(),
+        };
+
+        let mut results: Vec<SearchFileRes> = Vec::new();
+        let mut stats = CorpusStats::default();
+        for path in paths {
+            let sss = match search_file_content(&PathBuf::from(&path), search, &compiled, &options)
+            {
+                Ok(sss) => sss,
+                Err(e) => {
+                    debug!("process_file error: {}", e);
+                    continue;
+                }
+            };
+
+            if let Some(len) = sss.text_len {
+                stats.add_file(&path, len, !sss.is_empty());
+            }
+            if !sss.is_empty() {
+                results.push(sss.into_res(path, &options));
+            }
+        }
+
+        rank_results(
+            &mut results,
+            &stats,
+            &|name| file_name_matches(name, search, &compiled, options.mode),
+            &options.ranking,
+        );
+
+        Ok(results)
+    }
+}
+
+// Update the index stored at `index_path` with the changes of `dir_path`, then search with it
+pub fn search_in_dir_indexed_with_options(
+    dir_path: &Path,
+    index_path: &str,
+    search: &str,
+    options: &SearchOptions,
+) -> Result<Vec<SearchFileRes>, io::Error> {
+    let mut index = SearchIndex::open(index_path, options);
+    let stats = index.update(dir_path, &options.filter, Some(Path::new(index_path)))?;
+
+    if stats.added + stats.updated + stats.removed > 0 {
+        if let Err(e) = index.save(index_path) {
+            return Err(io::Error::other(format!("save search index error: {}", e)));
+        }
+    }
+
+    index.search(search, options)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn search_in_dir_indexed(
+    dir_path: &Path,
+    index_path: &str,
+    search: &str,
+    is_re_mode: bool,
+    context_size: usize,
+    wrapper_prefix: &str,
+    wrapper_postfix: &str,
+    html_like_exts: &[String],
+) -> Result<Vec<SearchFileRes>, io::Error> {
+    let options = SearchOptions::from_args(
+        is_re_mode,
+        context_size,
+        wrapper_prefix,
+        wrapper_postfix,
+        html_like_exts,
+    );
+
+    search_in_dir_indexed_with_options(dir_path, index_path, search, &options)
+}
+
+#[test]
+fn test_search_in_dir_indexed() {
+    use crate::search::search_in_dir;
+
+    let dir = std::env::temp_dir().join("fivim_rs_utils_test_search_index");
+    let _ = std::fs::remove_dir_all(&dir);
+    let notes = dir.join("notes");
+    let index_path = path_buf_to_string(dir.join("index.json"));
+
+    let _ = fs_file::write_str(
+        &path_buf_to_string(notes.join("a.md")),
+        "Unable to obtain core configuration information",
+    );
+    let _ = fs_file::write_str(
+        &path_buf_to_string(notes.join("sub").join("b.md")),
+        "语种: 中文,西班牙语,英语",
+    );
+    let _ = fs_file::write_str(
+        &path_buf_to_string(notes.join("c.md")),
+        "see [link](http://example.com/needle) ok",
+    );
+    let exts: Vec<String> = [].to_vec();
+
+    for search in ["config", "core conf", "语", "needle", "nothing"] {
+        let mut expected = search_in_dir(&notes, search, false, 10, "<b>", "</b>", &exts).unwrap();
+        let mut indexed =
+            search_in_dir_indexed(&notes, &index_path, search, false, 10, "<b>", "</b>", &exts)
+                .unwrap();
+        expected.sort_by(|a, b| a.path.cmp(&b.path));
+        indexed.sort_by(|a, b| a.path.cmp(&b.path));
+
+        assert_eq!(expected.len(), indexed.len());
+        for (e, i) in expected.iter().zip(indexed.iter()) {
+            assert_eq!(e.path, i.path);
+            assert_eq!(e.matches, i.matches);
+        }
+    }
+
+    let options = SearchOptions::from_args(false, 10, "<b>", "</b>", &exts);
+    let mut index = SearchIndex::open(&index_path, &options);
+    assert_eq!(index.file_count(), 3);
+    assert_eq!(
+        SearchIndex::open(&index_path, &SearchOptions::new()).file_count(),
+        0
+    );
+
+    let _ = std::fs::remove_file(notes.join("a.md"));
+    let stats = index.update(&notes, &options.filter, None).unwrap();
+    assert_eq!(stats.removed, 1);
+    assert_eq!(stats.unchanged, 2);
+    assert!(index.candidates("config").is_empty());
+    assert!(index
+        .update(&dir.join("missing"), &options.filter, None)
+        .is_err());
+    assert_eq!(index.file_count(), 2);
+
+    let mut options = SearchOptions::new();
+    let other_index = path_buf_to_string(dir.join("other.json"));
+    options.index_path = Some(other_index.clone());
+    let results = crate::search::search_in_dir_with_options(&notes, "语", &options).unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(SearchIndex::open(&other_index, &options).file_count(), 2);
+
+    // The word mode narrows the files by their terms, another tokenizer rebuilds the index
+    let _ = fs_file::write_str(
+        &path_buf_to_string(notes.join("d.md")),
+        "configuration of the core",
+    );
+    options.mode = SearchMode::Word;
+    let index = SearchIndex::open(&other_index, &options);
+    assert!(index.word_candidates("core").is_empty());
+    let results = crate::search::search_in_dir_with_options(&notes, "core", &options).unwrap();
+    assert_eq!(results.len(), 1);
+    assert!(results[0].path.ends_with("d.md"));
+    let index = SearchIndex::open(&other_index, &options);
+    assert_eq!(index.word_candidates("core").len(), 1);
+    assert_eq!(index.word_candidates("语").len(), 1);
+    assert!(index.word_candidates("conf").is_empty());
+    assert_eq!(index.candidates("conf").len(), 1);
+
+    options.tokenizer = Some(std::sync::Arc::new(crate::search::WordTokenizer));
+    assert_eq!(SearchIndex::open(&other_index, &options).file_count(), 0);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
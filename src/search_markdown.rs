@@ -0,0 +1,538 @@
+use serde::{Deserialize, Serialize};
+
+use crate::search_html::{decode_entity, parse_tag, ExtractedText, FIELD_HEADING};
+
+// Names of the fields of a markdown document, front-matter fields are named `fm.<key>`
+pub const FIELD_LINK: &str = "link";
+pub const FIELD_URL: &str = "url";
+pub const FIELD_CODE: &str = "code";
+pub const FRONT_MATTER_PREFIX: &str = "fm.";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MarkdownOptions {
+    // Leave the content of fenced code blocks out of the text
+    pub skip_code_blocks: bool,
+    // Leave link targets and bare URLs out of the text
+    pub skip_urls: bool,
+    // Extract the values of the front-matter, otherwise it is left out
+    pub front_matter: bool,
+}
+
+impl Default for MarkdownOptions {
+    fn default() -> Self {
+        MarkdownOptions::new()
+    }
+}
+
+impl MarkdownOptions {
+    pub fn new() -> MarkdownOptions {
+        MarkdownOptions {
+            skip_code_blocks: false,
+            skip_urls: true,
+            front_matter: true,
+        }
+    }
+}
+
+// The lines of `src` with their byte offsets, without line endings
+fn split_lines(src: &str) -> Vec<(usize, &str)> {
+    let mut lines: Vec<(usize, &str)> = Vec::new();
+    let mut start = 0;
+    for line in src.split_inclusive('\n') {
+        let content = line.trim_end_matches(['\n', '\r']);
+        lines.push((start, content));
+        start += line.len();
+    }
+    lines
+}
+
+fn unquote(s: &str, start: usize) -> (usize, usize) {
+    let bytes = s.as_bytes();
+    if s.len() >= 2 && (bytes[0] == b'"' || bytes[0] == b'\'') && bytes[s.len() - 1] == bytes[0] {
+        return (start + 1, start + s.len() - 1);
+    }
+    (start, start + s.len())
+}
+
+// Offset and content of `s` without its surrounding whitespace
+fn trim_range(s: &str, start: usize) -> (usize, &str) {
+    let trimmed = s.trim_start();
+    (start + s.len() - trimmed.len(), trimmed.trim_end())
+}
+
+fn push_front_matter_value(
+    res: &mut ExtractedText,
+    src: &str,
+    key: &str,
+    start: usize,
+    end: usize,
+) {
+    let (start, end) = unquote(&src[start..end], start);
+    if start >= end {
+        return;
+    }
+    let field_start = res.text.len();
+    res.push_literal(&src[start..end], start);
+    res.add_field(&format!("{}{}", FRONT_MATTER_PREFIX, key), field_start);
+    res.push_newline(end);
+}
+
+// The values of a YAML front-matter, as `key: value`, `key: [a, b]` or a `- item` list
+fn extract_front_matter(res: &mut ExtractedText, src: &str, lines: &[(usize, &str)]) {
+    let mut list_key = String::new();
+    for (start, line) in lines {
+        let (start, trimmed) = trim_range(line, *start);
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+
+        if let Some(item) = trimmed.strip_prefix("- ") {
+            if !list_key.is_empty() {
+                let (s, item) = trim_range(item, start + 2);
+                push_front_matter_value(res, src, &list_key, s, s + item.len());
+            }
+            continue;
+        }
+
+        let (raw_key, value) = match trimmed.split_once(':') {
+            Some(kv) => kv,
+            None => continue,
+        };
+        let key = raw_key.trim().to_lowercase();
+        let (value_start, value) = trim_range(value, start + raw_key.len() + 1);
+        list_key = String::new();
+
+        if value.is_empty() {
+            list_key = key;
+        } else if value.starts_with('[') && value.ends_with(']') {
+            let mut item_start = value_start + 1;
+            for item in value[1..value.len() - 1].split(',') {
+                let (s, item_trimmed) = trim_range(item, item_start);
+                push_front_matter_value(res, src, &key, s, s + item_trimmed.len());
+                item_start += item.len() + 1;
+            }
+        } else {
+            push_front_matter_value(res, src, &key, value_start, value_start + value.len());
+        }
+    }
+}
+
+// The fence char and length if `trimmed` opens a fenced code block
+fn fence_open(trimmed: &str) -> Option<(char, usize)> {
+    for c in ['`', '~'] {
+        let len = trimmed.chars().take_while(|x| *x == c).count();
+        if len >= 3 {
+            return Some((c, len));
+        }
+    }
+    None
+}
+
+fn is_fence_close(trimmed: &str, fence: (char, usize)) -> bool {
+    let len = trimmed.chars().take_while(|x| *x == fence.0).count();
+    len >= fence.1 && trimmed[len..].trim().is_empty()
+}
+
+fn is_thematic_break(trimmed: &str) -> bool {
+    let chars: Vec<char> = trimmed.chars().filter(|c| !c.is_whitespace()).collect();
+    chars.len() >= 3 && ['-', '*', '_'].iter().any(|m| chars.iter().all(|c| c == m))
+}
+
+fn is_setext_underline(line: &str) -> bool {
+    let trimmed = line.trim();
+    !trimmed.is_empty() && (trimmed.chars().all(|c| c == '=') || trimmed.chars().all(|c| c == '-'))
+}
+
+// `[label]: url` lines, which are not rendered
+fn is_reference_definition(trimmed: &str) -> bool {
+    trimmed.starts_with('[')
+        && !trimmed.starts_with("[^")
+        && trimmed
+            .find("]:")
+            .is_some_and(|p| !trimmed[..p].contains(']'))
+}
+
+// The range of the text of an ATX heading such as `## Title ##`
+fn atx_heading(line: &str) -> Option<(usize, usize)> {
+    let indent = line.len() - line.trim_start().len();
+    let rest = &line[indent..];
+    let level = rest.chars().take_while(|c| *c == '#').count();
+    if level == 0 || level > 6 {
+        return None;
+    }
+    let after = &rest[level..];
+    if !after.is_empty() && !after.starts_with([' ', '\t']) {
+        return None;
+    }
+
+    let mut content = after.trim();
+    let closing = content.trim_end_matches('#');
+    if closing.is_empty() || closing.ends_with([' ', '\t']) {
+        content = closing.trim_end();
+    }
+    if content.is_empty() {
+        return Some((line.len(), line.len()));
+    }
+
+    let start = indent + level + (after.len() - after.trim_start().len());
+    Some((start, start + content.len()))
+}
+
+// Offset of the content of a line after its block quote, list and task markers
+fn block_content_start(line: &str) -> usize {
+    let mut i = line.len() - line.trim_start().len();
+    while line[i..].starts_with('>') {
+        i += 1;
+        i += line[i..].len() - line[i..].trim_start().len();
+    }
+
+    let rest = &line[i..];
+    if rest.starts_with("- ") || rest.starts_with("* ") || rest.starts_with("+ ") {
+        i += 2;
+    } else {
+        let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+        if digits > 0 && (rest[digits..].starts_with(". ") || rest[digits..].starts_with(") ")) {
+            i += digits + 2;
+        }
+    }
+
+    for task in ["[ ] ", "[x] ", "[X] "] {
+        if line[i..].starts_with(task) {
+            i += task.len();
+        }
+    }
+
+    i
+}
+
+// Byte offset of the `close` matching the `open` at `start`, within `end`
+fn find_closing(src: &str, start: usize, end: usize, open: u8, close: u8) -> Option<usize> {
+    let bytes = src.as_bytes();
+    let mut depth = 0;
+    let mut i = start;
+    while i < end {
+        match bytes[i] {
+            b'\\' => i += 1,
+            b if b == open => depth += 1,
+            b if b == close => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+struct InlineLink {
+    text: (usize, usize),
+    url: Option<(usize, usize)>,
+    end: usize,
+}
+
+// Parse `[text](url "title")` or `[text][label]` at the `[` at `start`
+fn parse_link(src: &str, start: usize, end: usize) -> Option<InlineLink> {
+    let text_end = find_closing(src, start, end, b'[', b']')?;
+    let bytes = src.as_bytes();
+    match bytes.get(text_end + 1) {
+        Some(b'(') if text_end + 1 < end => {
+            let close = find_closing(src, text_end + 1, end, b'(', b')')?;
+            let (url_start, inner) = trim_range(&src[text_end + 2..close], text_end + 2);
+            let url = inner.split_whitespace().next().unwrap_or("");
+            let url = url.trim_start_matches('<').trim_end_matches('>');
+            let url_start = url_start + inner.find(url).unwrap_or(0);
+            Some(InlineLink {
+                text: (start + 1, text_end),
+                url: Some((url_start, url_start + url.len())),
+                end: close + 1,
+            })
+        }
+        Some(b'[') if text_end + 1 < end => {
+            let close = find_closing(src, text_end + 1, end, b'[', b']')?;
+            Some(InlineLink {
+                text: (start + 1, text_end),
+                url: None,
+                end: close + 1,
+            })
+        }
+        _ => None,
+    }
+}
+
+fn bare_url_end(src: &str, start: usize, end: usize) -> Option<usize> {
+    let rest = &src[start..end];
+    if !(rest.starts_with("http://") || rest.starts_with("https://") || rest.starts_with("www.")) {
+        return None;
+    }
+    if start > 0 && !src[..start].ends_with([' ', '\t', '\n', '(', '>']) {
+        return None;
+    }
+
+    let len = rest.find(char::is_whitespace).unwrap_or(rest.len());
+    let url = rest[..len].trim_end_matches(['.', ',', ';', ':', '!', '?', ')']);
+    Some(start + url.len())
+}
+
+fn push_url(
+    res: &mut ExtractedText,
+    src: &str,
+    start: usize,
+    end: usize,
+    options: &MarkdownOptions,
+) {
+    if options.skip_urls || start >= end {
+        return;
+    }
+    res.push_space(start);
+    let field_start = res.text.len();
+    res.push_literal(&src[start..end], start);
+    res.add_field(FIELD_URL, field_start);
+}
+
+// Push the rendered text of the inline markdown `src[start..end]`
+fn push_inline(
+    res: &mut ExtractedText,
+    src: &str,
+    start: usize,
+    end: usize,
+    options: &MarkdownOptions,
+) {
+    let bytes = src.as_bytes();
+    let mut run_start = start;
+    let mut i = start;
+    while i < end {
+        let b = bytes[i];
+        match b {
+            b'\\' if i + 1 < end && bytes[i + 1].is_ascii_punctuation() => {
+                res.push_literal(&src[run_start..i], run_start);
+                run_start = i + 1;
+                i += 2;
+                continue;
+            }
+            b'`' => {
+                let n = src[i..end].bytes().take_while(|c| *c == b'`').count();
+                let ticks = &src[i..i + n];
+                let close = src[i + n..end].find(ticks).map(|p| i + n + p);
+                if let Some(close) = close {
+                    res.push_literal(&src[run_start..i], run_start);
+                    let (s, code) = trim_range(&src[i + n..close], i + n);
+                    res.push_literal(code, s);
+                    i = close + n;
+                    run_start = i;
+                    continue;
+                }
+                i += n;
+                continue;
+            }
+            b'!' | b'[' => {
+                let link_start = if b == b'!' { i + 1 } else { i };
+                if bytes.get(link_start) == Some(&b'[') {
+                    if let Some(link) = parse_link(src, link_start, end) {
+                        res.push_literal(&src[run_start..i], run_start);
+                        let field_start = res.text.len();
+                        push_inline(res, src, link.text.0, link.text.1, options);
+                        if b == b'[' {
+                            res.add_field(FIELD_LINK, field_start);
+                        }
+                        if let Some((us, ue)) = link.url {
+                            push_url(res, src, us, ue, options);
+                        }
+                        i = link.end;
+                        run_start = i;
+                        continue;
+                    }
+                }
+            }
+            b'<' => {
+                let close = src[i..end].find('>').map(|p| i + p);
+                if let Some(close) = close {
+                    let inner = &src[i + 1..close];
+                    let is_autolink = !inner.contains(char::is_whitespace)
+                        && (inner.contains("://") || inner.contains('@'));
+                    if is_autolink {
+                        res.push_literal(&src[run_start..i], run_start);
+                        push_url(res, src, i + 1, close, options);
+                        i = close + 1;
+                        run_start = i;
+                        continue;
+                    }
+                }
+                // A `<` without a `>` later on the line is text, such as `x<y`
+                if let Some(tag) = parse_tag(&src[..end], i).filter(|t| t.is_closed) {
+                    res.push_literal(&src[run_start..i], run_start);
+                    i = tag.end;
+                    run_start = i;
+                    continue;
+                }
+            }
+            b'&' => {
+                if let Some((decoded, entity_end)) = decode_entity(&src[..end], i) {
+                    res.push_literal(&src[run_start..i], run_start);
+                    res.push_mapped(&decoded, i, entity_end);
+                    i = entity_end;
+                    run_start = i;
+                    continue;
+                }
+            }
+            b'*' | b'~' | b'_' => {
+                let n = src[i..end].bytes().take_while(|c| *c == b).count();
+                // Only a run at a word boundary is an emphasis delimiter, inside a word such as
+                // snake_case or between spaces such as `2 * 3` it is text
+                let before = src[start..i].chars().last();
+                let after = src[i + n..end].chars().next();
+                let is_word = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric());
+                let is_space = |c: Option<char>| c.is_none_or(|c| c.is_whitespace());
+                let is_delimiter =
+                    !(is_word(before) && is_word(after) || is_space(before) && is_space(after));
+                if is_delimiter {
+                    res.push_literal(&src[run_start..i], run_start);
+                    run_start = i + n;
+                }
+                i += n;
+                continue;
+            }
+            b'h' | b'w' => {
+                if let Some(url_end) = bare_url_end(src, i, end) {
+                    res.push_literal(&src[run_start..i], run_start);
+                    push_url(res, src, i, url_end, options);
+                    i = url_end;
+                    run_start = i;
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    res.push_literal(&src[run_start..end], run_start);
+}
+
+// Extract the rendered text of a markdown document. Headings, link texts, URLs, code blocks
+// and front-matter values are also recorded as fields, so queries can target them.
+pub fn extract_markdown(src: &str, options: &MarkdownOptions) -> ExtractedText {
+    let mut res = ExtractedText::default();
+    let lines = split_lines(src);
+
+    let mut i = 0;
+    if lines.first().is_some_and(|(_, l)| l.trim_end() == "---") {
+        let close = lines[1..]
+            .iter()
+            .position(|(_, l)| l.trim_end() == "---" || l.trim_end() == "...");
+        if let Some(close) = close {
+            if options.front_matter {
+                extract_front_matter(&mut res, src, &lines[1..close + 1]);
+            }
+            i = close + 2;
+        }
+    }
+
+    let mut fence: Option<(char, usize)> = None;
+    while i < lines.len() {
+        let (start, line) = lines[i];
+        let line_end = start + line.len();
+        let trimmed = line.trim_start();
+
+        if let Some(f) = fence {
+            if is_fence_close(trimmed, f) {
+                fence = None;
+            } else if !options.skip_code_blocks {
+                let field_start = res.text.len();
+                res.push_literal(line, start);
+                res.add_field(FIELD_CODE, field_start);
+                res.push_mapped("\n", line_end, line_end);
+            }
+            i += 1;
+            continue;
+        }
+
+        if let Some(f) = fence_open(trimmed) {
+            fence = Some(f);
+            res.push_newline(start);
+        } else if trimmed.is_empty() || is_reference_definition(trimmed) {
+            res.push_newline(start);
+        } else if let Some((hs, he)) = atx_heading(line) {
+            let field_start = res.text.len();
+            push_inline(&mut res, src, start + hs, start + he, options);
+            res.add_field(FIELD_HEADING, field_start);
+            res.push_newline(line_end);
+        } else if i + 1 < lines.len()
+            && is_setext_underline(lines[i + 1].1)
+            && block_content_start(line) == line.len() - trimmed.len()
+        {
+            let field_start = res.text.len();
+            push_inline(&mut res, src, line_end - trimmed.len(), line_end, options);
+            res.add_field(FIELD_HEADING, field_start);
+            res.push_newline(line_end);
+            i += 1;
+        } else if is_thematic_break(trimmed) {
+            res.push_newline(start);
+        } else {
+            push_inline(
+                &mut res,
+                src,
+                start + block_content_start(line),
+                line_end,
+                options,
+            );
+            res.push_newline(line_end);
+        }
+        i += 1;
+    }
+
+    res.trim_end();
+    res
+}
+
+#[test]
+fn test_extract_markdown() {
+    let src = "---\ntitle: \"My note\"\ntags: [rust, search]\naliases:\n  - note\n---\n\
+        # Intro #\n\nSee [the **docs**](https://docs.rs \"Docs\") and https://example.com.\n\
+        - [x] use `snake_case` &amp; *stars*\n\nSetext\n======\n\n```rust\nlet a = 1;\n```\n\
+        [docs]: https://docs.rs\n";
+
+    let doc = extract_markdown(src, &MarkdownOptions::new());
+    assert_eq!(
+        doc.text,
+        "My note\nrust\nsearch\nnote\nIntro\nSee the docs and .\nuse snake_case & stars\nSetext\nlet a = 1;"
+    );
+
+    let field = |name: &str| -> Vec<&str> {
+        doc.fields
+            .iter()
+            .filter(|f| f.name == name)
+            .map(|f| &doc.text[f.start..f.end])
+            .collect()
+    };
+    assert_eq!(field("fm.title"), ["My note"]);
+    assert_eq!(field("fm.tags"), ["rust", "search"]);
+    assert_eq!(field("fm.aliases"), ["note"]);
+    assert_eq!(field(FIELD_HEADING), ["Intro", "Setext"]);
+    assert_eq!(field(FIELD_LINK), ["the docs"]);
+    assert_eq!(field(FIELD_CODE), ["let a = 1;"]);
+
+    let start = doc.text.find("snake_case").unwrap();
+    let (s, e) = doc.source_span(start, start + 10);
+    assert_eq!(&src[s..e], "snake_case");
+
+    let mut options = MarkdownOptions::new();
+    options.skip_urls = false;
+    options.skip_code_blocks = true;
+    options.front_matter = false;
+    let doc = extract_markdown(src, &options);
+    assert!(doc
+        .text
+        .starts_with("Intro\nSee the docs https://docs.rs and https://example.com."));
+    assert!(!doc.text.contains("let a"));
+
+    let doc = extract_markdown(
+        "a x<y and zebra here\nsnake_case a*b*c 2 * 3 ~~old~~ **(bold)** <b>tag</b>\nnext",
+        &MarkdownOptions::new(),
+    );
+    assert_eq!(
+        doc.text,
+        "a x<y and zebra here\nsnake_case a*b*c 2 * 3 old (bold) tag\nnext"
+    );
+}
@@ -0,0 +1,284 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::{max, min};
+use std::collections::BTreeMap;
+
+use crate::search::extract_multibyte_safe;
+
+// A match with its position in the searched text. Lines and columns start from 1, columns and
+// char offsets count Unicode scalar values. For html like files the positions refer to the
+// extracted text.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SearchMatch {
+    pub line: usize,
+    pub column: usize,
+    pub byte_start: usize,
+    pub byte_end: usize,
+    pub char_start: usize,
+    pub char_end: usize,
+    pub text: String,
+    pub context_before: String,
+    pub context_after: String,
+}
+
+impl SearchMatch {
+    // The wrapped snippet, the same as the output of `find_matches`.
+    // A match without text (a query matching only by filters) is rendered without wrappers.
+    pub fn render(&self, prefix: &str, postfix: &str) -> String {
+        if self.text.is_empty() {
+            return format!("{}{}", self.context_before, self.context_after);
+        }
+
+        format!(
+            "{}{}{}{}{}",
+            self.context_before, prefix, self.text, postfix, self.context_after
+        )
+    }
+}
+
+pub fn render_matches(matches: &[SearchMatch], prefix: &str, postfix: &str) -> Vec<String> {
+    matches.iter().map(|m| m.render(prefix, postfix)).collect()
+}
+
+// Build the positions of the byte ranges of `string`, with `context_size` bytes of context
+// on both sides like `wrap_text`
+pub fn build_matches(
+    string: &str,
+    spans: &[(usize, usize)],
+    context_size: usize,
+) -> Vec<SearchMatch> {
+    let line_starts: Vec<usize> = std::iter::once(0)
+        .chain(string.match_indices('\n').map(|(i, _)| i + 1))
+        .collect();
+
+    // Char offset of `byte` counted from a known (byte, char) position before it
+    let count_from = |from: (usize, usize), byte: usize| -> usize {
+        from.1 + string[from.0..byte].chars().count()
+    };
+
+    // Char offsets are counted from the end of the previous match, spans are usually sorted.
+    // The char offset of the current line start is kept, so overlapping matches on a long line
+    // do not rescan it from its start.
+    let mut cursor = (0, 0);
+    let mut line_cache: Option<(usize, usize)> = None;
+
+    let mut matches: Vec<SearchMatch> = Vec::new();
+    for &(start, end) in spans {
+        let line_index = line_starts.partition_point(|s| *s <= start) - 1;
+        let line_start = line_starts[line_index];
+
+        let line_start_char = match line_cache {
+            Some((index, c)) if index == line_index => c,
+            _ if line_start >= cursor.0 => count_from(cursor, line_start),
+            _ => count_from((0, 0), line_start),
+        };
+        line_cache = Some((line_index, line_start_char));
+
+        let char_start = if start >= cursor.0 {
+            count_from(cursor, start)
+        } else {
+            count_from((line_start, line_start_char), start)
+        };
+        let char_end = count_from((start, char_start), end);
+        cursor = (end, char_end);
+
+        let context_before =
+            extract_multibyte_safe(string, start.saturating_sub(context_size), start)
+                .unwrap_or_default();
+        let context_after =
+            extract_multibyte_safe(string, end, min(string.len(), end + context_size))
+                .unwrap_or_default();
+
+        matches.push(SearchMatch {
+            line: line_index + 1,
+            column: char_start - line_start_char + 1,
+            byte_start: start,
+            byte_end: end,
+            char_start,
+            char_end,
+            text: string[start..end].to_string(),
+            context_before,
+            context_after,
+        });
+    }
+
+    matches
+}
+
+// A line of a `LineContext`. Numbers start from 1, `ranges` are the byte ranges of the matches
+// in `text`, empty for the context lines.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MatchLine {
+    pub number: usize,
+    pub text: String,
+    pub ranges: Vec<(usize, usize)>,
+}
+
+impl MatchLine {
+    pub fn is_match(&self) -> bool {
+        !self.ranges.is_empty()
+    }
+}
+
+// Consecutive lines around one or more matches like a block of `grep -n -C`, the contexts of
+// close matches are merged into one block
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LineContext {
+    pub lines: Vec<MatchLine>,
+}
+
+impl LineContext {
+    // `12:line` for the matching lines and `11-line` for the context lines, matches wrapped
+    pub fn render(&self, prefix: &str, postfix: &str) -> String {
+        let mut res: Vec<String> = Vec::new();
+        for line in &self.lines {
+            let mut s = String::new();
+            let mut pos = 0;
+            for &(start, end) in &line.ranges {
+                s.push_str(&line.text[pos..start]);
+                if start < end {
+                    s.push_str(prefix);
+                    s.push_str(&line.text[start..end]);
+                    s.push_str(postfix);
+                }
+                pos = end;
+            }
+            s.push_str(&line.text[pos..]);
+
+            let sep = if line.is_match() { ':' } else { '-' };
+            res.push(format!("{}{}{}", line.number, sep, s));
+        }
+
+        res.join("\n")
+    }
+}
+
+// Group the byte ranges of `string` by line with `context_lines` lines before and after each
+// matching line. A range over several lines marks all of them as matching.
+pub fn build_line_contexts(
+    string: &str,
+    spans: &[(usize, usize)],
+    context_lines: usize,
+) -> Vec<LineContext> {
+    let line_starts: Vec<usize> = std::iter::once(0)
+        .chain(string.match_indices('\n').map(|(i, _)| i + 1))
+        .collect();
+    // Line ends without the line break
+    let line_ends: Vec<usize> = (0..line_starts.len())
+        .map(|i| {
+            let end = line_starts
+                .get(i + 1)
+                .map(|s| s - 1)
+                .unwrap_or(string.len());
+            if end > line_starts[i] && string.as_bytes()[end - 1] == b'\r' {
+                end - 1
+            } else {
+                end
+            }
+        })
+        .collect();
+    let line_of = |byte: usize| line_starts.partition_point(|s| *s <= byte) - 1;
+
+    let mut ranges: BTreeMap<usize, Vec<(usize, usize)>> = BTreeMap::new();
+    for &(start, end) in spans {
+        let first = line_of(start);
+        let last = line_of(max(start, end.saturating_sub(1)));
+        for i in first..=last {
+            let (ls, le) = (line_starts[i], line_ends[i]);
+            let r = (start.clamp(ls, le) - ls, end.clamp(ls, le) - ls);
+            ranges.entry(i).or_default().push(r);
+        }
+    }
+
+    // Line index ranges of the blocks, merged when they overlap or touch
+    let mut blocks: Vec<(usize, usize)> = Vec::new();
+    for &i in ranges.keys() {
+        let from = i.saturating_sub(context_lines);
+        let to = min(i + context_lines, line_starts.len() - 1);
+        match blocks.last_mut() {
+            Some(last) if from <= last.1 + 1 => last.1 = max(last.1, to),
+            _ => blocks.push((from, to)),
+        }
+    }
+
+    blocks
+        .iter()
+        .map(|&(from, to)| LineContext {
+            lines: (from..=to)
+                .map(|i| MatchLine {
+                    number: i + 1,
+                    text: string[line_starts[i]..line_ends[i]].to_string(),
+                    ranges: ranges.get(&i).map(|r| merge_ranges(r)).unwrap_or_default(),
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+fn merge_ranges(ranges: &[(usize, usize)]) -> Vec<(usize, usize)> {
+    let mut sorted = ranges.to_vec();
+    sorted.sort();
+
+    let mut res: Vec<(usize, usize)> = Vec::new();
+    for (start, end) in sorted {
+        match res.last_mut() {
+            Some(last) if start < last.1 => last.1 = max(last.1, end),
+            _ => res.push((start, end)),
+        }
+    }
+    res
+}
+
+#[test]
+fn test_build_matches() {
+    let string = "第一行 rust\nsecond <b>rust</b> line";
+    let spans: Vec<(usize, usize)> = string
+        .match_indices("rust")
+        .map(|(i, m)| (i, i + m.len()))
+        .collect();
+
+    let matches = build_matches(string, &spans, 4);
+    assert_eq!(matches.len(), 2);
+    assert_eq!((matches[0].line, matches[0].column), (1, 5));
+    assert_eq!((matches[0].char_start, matches[0].char_end), (4, 8));
+    assert_eq!(matches[0].context_before, "行 ");
+    assert_eq!((matches[1].line, matches[1].column), (2, 11));
+    assert_eq!(matches[1].context_before, " <b>");
+    assert_eq!(matches[1].context_after, "</b>");
+
+    assert_eq!(
+        render_matches(&matches, "[", "]"),
+        ["行 [rust]\nsec", " <b>[rust]</b>"]
+    );
+
+    let head = build_matches(string, &[(0, 0)], 6);
+    assert_eq!(head[0].render("[", "]"), "第一");
+
+    // Overlapping spans on one line, then a span back on the first line
+    let unsorted = build_matches(string, &[(17, 27), (21, 24), (10, 14)], 0);
+    let chars: Vec<(usize, usize, usize)> = unsorted
+        .iter()
+        .map(|m| (m.line, m.column, m.char_start))
+        .collect();
+    assert_eq!(chars, [(2, 3, 11), (2, 7, 15), (1, 5, 4)]);
+}
+
+#[test]
+fn test_build_line_contexts() {
+    let string = "one\ntwo rust\r\nthree\nfour\nfive rust rust\nsix\nseven\neight\nnine rust";
+    let spans: Vec<(usize, usize)> = string
+        .match_indices("rust")
+        .map(|(i, m)| (i, i + m.len()))
+        .collect();
+
+    let blocks = build_line_contexts(string, &spans, 1);
+    assert_eq!(blocks.len(), 2);
+    assert_eq!(
+        blocks[0].render("[", "]"),
+        "1-one\n2:two [rust]\n3-three\n4-four\n5:five [rust] [rust]\n6-six"
+    );
+    assert_eq!(blocks[1].render("[", "]"), "8-eight\n9:nine [rust]");
+    assert_eq!(blocks[0].lines[1].ranges, [(4, 8)]);
+
+    let multi = build_line_contexts(string, &[(8, 19)], 0);
+    assert_eq!(multi[0].render("[", "]"), "2:two [rust]\n3:[three]");
+}
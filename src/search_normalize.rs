@@ -0,0 +1,172 @@
+use serde::{Deserialize, Serialize};
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::search::wrap_text;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Normalization {
+    None,
+    // Canonical composition, "e\u{301}" matches "é"
+    Nfc,
+    // Compatibility composition, full width "ＡＢＣ" matches "ABC" and "ﬁ" matches "fi"
+    Nfkc,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PlainMatchOptions {
+    pub ignore_case: bool,
+    pub ignore_accents: bool,
+    pub normalization: Normalization,
+}
+
+impl Default for PlainMatchOptions {
+    fn default() -> Self {
+        PlainMatchOptions::new()
+    }
+}
+
+impl PlainMatchOptions {
+    pub fn new() -> PlainMatchOptions {
+        PlainMatchOptions {
+            ignore_case: false,
+            ignore_accents: false,
+            normalization: Normalization::None,
+        }
+    }
+
+    // Whether the text has to be folded before matching, or a byte comparison is enough
+    pub fn is_folding(&self) -> bool {
+        self.ignore_case || self.ignore_accents || self.normalization != Normalization::None
+    }
+}
+
+fn fold_grapheme(grapheme: &str, options: &PlainMatchOptions) -> String {
+    let mut s: String = match options.normalization {
+        Normalization::None => grapheme.to_string(),
+        Normalization::Nfc => grapheme.nfc().collect(),
+        Normalization::Nfkc => grapheme.nfkc().collect(),
+    };
+
+    if options.ignore_accents {
+        s = s.nfd().filter(|c| !is_combining_mark(*c)).nfc().collect();
+    }
+
+    if options.ignore_case {
+        s = s.chars().flat_map(|c| c.to_lowercase()).collect();
+    }
+
+    s
+}
+
+// Fold the text grapheme by grapheme, every entry of the returned list is
+// (start in the folded text, start in the original text, end in the original text)
+fn fold_with_map(text: &str, options: &PlainMatchOptions) -> (String, Vec<(usize, usize, usize)>) {
+    let mut folded = String::with_capacity(text.len());
+    let mut map: Vec<(usize, usize, usize)> = Vec::new();
+
+    for (start, grapheme) in text.grapheme_indices(true) {
+        map.push((folded.len(), start, start + grapheme.len()));
+        folded.push_str(&fold_grapheme(grapheme, options));
+    }
+
+    (folded, map)
+}
+
+pub fn fold_text(text: &str, options: &PlainMatchOptions) -> String {
+    text.graphemes(true)
+        .map(|g| fold_grapheme(g, options))
+        .collect()
+}
+
+// Byte ranges of the original `string` matching `search` after both are folded.
+// A match that starts or ends inside a folded grapheme covers the whole grapheme.
+pub fn find_plain_spans(
+    string: &str,
+    search: &str,
+    options: &PlainMatchOptions,
+) -> Vec<(usize, usize)> {
+    if search.is_empty() {
+        return [].to_vec();
+    }
+    if !options.is_folding() {
+        return string
+            .match_indices(search)
+            .map(|(start, mat)| (start, start + mat.len()))
+            .collect();
+    }
+
+    let query = fold_text(search, options);
+    if query.is_empty() {
+        return [].to_vec();
+    }
+
+    let (folded, map) = fold_with_map(string, options);
+    let grapheme_at =
+        |folded_pos: usize| -> usize { map.partition_point(|m| m.0 <= folded_pos) - 1 };
+
+    let mut spans: Vec<(usize, usize)> = Vec::new();
+    for (folded_start, mat) in folded.match_indices(&query) {
+        let first = grapheme_at(folded_start);
+        let last = grapheme_at(folded_start + mat.len() - 1);
+        let (start, end) = (map[first].1, map[last].2);
+
+        match spans.last_mut() {
+            Some(prev) if start < prev.1 => prev.1 = prev.1.max(end),
+            _ => spans.push((start, end)),
+        }
+    }
+
+    spans
+}
+
+// Folding version of the plain mode of `find_matches`, the output has the same format
+pub fn find_matches_plain(
+    string: &str,
+    search: &str,
+    options: &PlainMatchOptions,
+    context_size: usize,
+    prefix: &str,
+    postfix: &str,
+) -> Vec<String> {
+    find_plain_spans(string, search, options)
+        .into_iter()
+        .map(|(start, end)| {
+            wrap_text(
+                string,
+                &string[start..end],
+                start,
+                end,
+                context_size,
+                prefix,
+                postfix,
+            )
+            .0
+        })
+        .collect()
+}
+
+#[test]
+fn test_find_plain_spans() {
+    let mut options = PlainMatchOptions::new();
+    options.ignore_case = true;
+    options.ignore_accents = true;
+
+    let string = "My Résumé, resume and RE\u{301}SUME\u{301}";
+    let spans = find_plain_spans(string, "resume", &options);
+    let found: Vec<&str> = spans.iter().map(|(s, e)| &string[*s..*e]).collect();
+    assert_eq!(found, ["Résumé", "resume", "RE\u{301}SUME\u{301}"]);
+
+    let res = find_matches_plain(string, "RÉSUMÉ", &options, 3, "<b>", "</b>");
+    assert_eq!(res[0], "My <b>Résumé</b>, r");
+
+    let mut options = PlainMatchOptions::new();
+    options.normalization = Normalization::Nfkc;
+    let string = "全角ＡＢＣ１２３と半角";
+    let spans = find_plain_spans(string, "BC12", &options);
+    assert_eq!(&string[spans[0].0..spans[0].1], "ＢＣ１２");
+
+    assert!(find_plain_spans(string, "abc", &options).is_empty());
+    assert!(find_plain_spans(string, "", &options).is_empty());
+}
@@ -0,0 +1,301 @@
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::search::{
+    compile_search, file_name_matches, search_path, walk_dir_files, CompiledSearch, SearchFileRes,
+    SearchOptions,
+};
+use crate::search_rank::{rank_results, CorpusStats};
+
+// Shared flag to stop a running search from another thread, clones share the same flag
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    flag: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchStatus {
+    Complete,
+    Cancelled,
+    MaxResults,
+    TimedOut,
+}
+
+// Results of `search_in_dir_parallel`, partial unless `status` is `Complete`.
+// `files_searched` also counts the files that could not be read.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ParallelSearchRes {
+    pub results: Vec<SearchFileRes>,
+    pub status: SearchStatus,
+    pub files_searched: usize,
+    pub files_total: usize,
+}
+
+// Decides when a search has to stop: cancellation, result limit or time budget
+pub(crate) struct StopCheck<'a> {
+    cancel: &'a CancelToken,
+    deadline: Option<Instant>,
+    max_results: usize,
+    found: AtomicUsize,
+    // Why the search stopped, set when it is first observed, so that a search which went
+    // through all the files is complete even if the budget is spent or the token cancelled after
+    reason: OnceLock<SearchStatus>,
+}
+
+impl<'a> StopCheck<'a> {
+    pub fn new(cancel: &'a CancelToken, options: &SearchOptions) -> StopCheck<'a> {
+        StopCheck {
+            cancel,
+            deadline: if options.time_budget_ms > 0 {
+                Some(Instant::now() + Duration::from_millis(options.time_budget_ms))
+            } else {
+                None
+            },
+            max_results: options.max_results,
+            found: AtomicUsize::new(0),
+            reason: OnceLock::new(),
+        }
+    }
+
+    // Count a matched file, false when it exceeds the result limit and has to be dropped
+    pub fn add_found(&self) -> bool {
+        let count = self.found.fetch_add(1, Ordering::SeqCst);
+        if self.max_results == 0 || count < self.max_results {
+            return true;
+        }
+        // A matched file was dropped
+        let _ = self.reason.set(SearchStatus::MaxResults);
+        false
+    }
+
+    pub fn status(&self) -> SearchStatus {
+        self.reason.get().copied().unwrap_or(SearchStatus::Complete)
+    }
+
+    // Call it only when there is more to search, a stop is then recorded in `status`
+    pub fn should_stop(&self) -> bool {
+        if self.reason.get().is_some() {
+            return true;
+        }
+        let reason = if self.cancel.is_cancelled() {
+            SearchStatus::Cancelled
+        } else if self.deadline.is_some_and(|d| Instant::now() >= d) {
+            SearchStatus::TimedOut
+        } else {
+            return false;
+        };
+        let _ = self.reason.set(reason);
+        true
+    }
+}
+
+// The files of `dir_path` passing `options.filter`, stops early when `stop` says so
+pub(crate) fn walk_files(
+    dir_path: &Path,
+    options: &SearchOptions,
+    stop: &StopCheck,
+) -> Result<Vec<PathBuf>, io::Error> {
+    walk_dir_files(dir_path, options, &|| stop.should_stop())
+}
+
+// Search `files` with `options.threads` workers until done or `stop` says so.
+// `on_file` is called from the workers after each searched file, also the ones that could not
+// be read, with its index in `files` and its matched results, several for an archive.
+// Returns the corpus stats and the count of searched files.
+pub(crate) fn search_files(
+    files: &[PathBuf],
+    search: &str,
+    compiled: &CompiledSearch,
+    options: &SearchOptions,
+    stop: &StopCheck,
+    on_file: &(dyn Fn(usize, Vec<SearchFileRes>) + Sync),
+) -> (CorpusStats, usize) {
+    let threads = if options.threads > 0 {
+        options.threads
+    } else {
+        thread::available_parallelism().map_or(1, |n| n.get())
+    };
+
+    let next = AtomicUsize::new(0);
+    let searched = AtomicUsize::new(0);
+    let stats: Mutex<CorpusStats> = Mutex::new(CorpusStats::default());
+
+    thread::scope(|scope| {
+        for _ in 0..threads.min(files.len()).max(1) {
+            scope.spawn(|| {
+                let mut local_stats = CorpusStats::default();
+                loop {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    let path = match files.get(i) {
+                        Some(p) => p,
+                        None => break,
+                    };
+                    if stop.should_stop() {
+                        break;
+                    }
+
+                    let mut results: Vec<SearchFileRes> = Vec::new();
+                    search_path(path, search, compiled, options, &mut |path_str, sss| {
+                        if let Some(len) = sss.text_len {
+                            local_stats.add_file(&path_str, len, !sss.is_empty());
+                        }
+                        if !sss.is_empty() && stop.add_found() {
+                            results.push(sss.into_res(path_str, options));
+                        }
+                    });
+                    searched.fetch_add(1, Ordering::SeqCst);
+                    on_file(i, results);
+                }
+                stats.lock().unwrap().merge(local_stats);
+            });
+        }
+    });
+
+    (stats.into_inner().unwrap(), searched.into_inner())
+}
+
+// Search the files of `dir_path` with `options.threads` workers. The search stops early when
+// `cancel` is cancelled, `options.max_results` files matched or `options.time_budget_ms`
+// is spent, the results found so far are returned with the reason in `status`.
+pub fn search_in_dir_parallel(
+    dir_path: &Path,
+    search: &str,
+    options: &SearchOptions,
+    cancel: &CancelToken,
+) -> Result<ParallelSearchRes, io::Error> {
+    let compiled = compile_search(search, options)?;
+    let stop = StopCheck::new(cancel, options);
+    let files = walk_files(dir_path, options, &stop)?;
+
+    let found: Mutex<Vec<(usize, SearchFileRes)>> = Mutex::new(Vec::new());
+    let (stats, searched) = search_files(&files, search, &compiled, options, &stop, &|i, res| {
+        found
+            .lock()
+            .unwrap()
+            .extend(res.into_iter().map(|r| (i, r)));
+    });
+
+    let mut found = found.into_inner().unwrap();
+    found.sort_by_key(|(i, _)| *i);
+    let mut results: Vec<SearchFileRes> = found.into_iter().map(|(_, r)| r).collect();
+
+    rank_results(
+        &mut results,
+        &stats,
+        &|name| file_name_matches(name, search, &compiled, options.mode),
+        &options.ranking,
+    );
+
+    Ok(ParallelSearchRes {
+        results,
+        status: stop.status(),
+        files_searched: searched,
+        files_total: files.len(),
+    })
+}
+
+#[test]
+fn test_search_in_dir_parallel() {
+    use crate::fs::path_buf_to_string;
+
+    let dir = std::env::temp_dir().join("fivim_rs_utils_test_search_parallel");
+    let _ = std::fs::remove_dir_all(&dir);
+    for i in 0..20 {
+        let _ = crate::fs_file::write_str(
+            &path_buf_to_string(dir.join(format!("d{}", i % 3)).join(format!("{:02}.md", i))),
+            &format!("note {} about rust", i),
+        );
+    }
+
+    let mut options = SearchOptions::new();
+    options.threads = 4;
+    let cancel = CancelToken::new();
+
+    let res = search_in_dir_parallel(&dir, "rust", &options, &cancel).unwrap();
+    assert_eq!(res.status, SearchStatus::Complete);
+    assert_eq!(res.results.len(), 20);
+    assert_eq!(res.files_searched, 20);
+
+    options.max_results = 20;
+    let res = search_in_dir_parallel(&dir, "rust", &options, &cancel).unwrap();
+    assert_eq!(res.status, SearchStatus::Complete);
+    assert_eq!(res.results.len(), 20);
+
+    let zip_path = dir.join("notes.zip");
+    let mut writer = zip::ZipWriter::new(std::fs::File::create(&zip_path).unwrap());
+    writer
+        .start_file("a.md", zip::write::FileOptions::default())
+        .unwrap();
+    std::io::Write::write_all(&mut writer, b"zipped rust").unwrap();
+    writer.finish().unwrap();
+    let _ = crate::fs_file::write_str(
+        &path_buf_to_string(dir.join("big.md")),
+        &"rust ".repeat(100),
+    );
+    options.max_results = 0;
+    options.max_memory = 100;
+    options.search_archives = true;
+    let res = search_in_dir_parallel(&dir, "rust", &options, &cancel).unwrap();
+    assert_eq!(res.results.len(), 21);
+    assert!(res
+        .results
+        .iter()
+        .any(|r| r.path.ends_with("notes.zip!/a.md")));
+    assert_eq!(res.files_searched, 22);
+    assert_eq!(res.files_total, 22);
+    let _ = std::fs::remove_file(&zip_path);
+    let _ = std::fs::remove_file(dir.join("big.md"));
+
+    options.max_results = 5;
+    let res = search_in_dir_parallel(&dir, "rust", &options, &cancel).unwrap();
+    assert_eq!(res.status, SearchStatus::MaxResults);
+    assert_eq!(res.results.len(), 5);
+
+    // The budget is spent after the first file
+    options.max_results = 0;
+    options.threads = 1;
+    options.time_budget_ms = 1;
+    let compiled = compile_search("rust", &options).unwrap();
+    let files = walk_dir_files(&dir, &options, &|| false).unwrap();
+    let stop = StopCheck::new(&cancel, &options);
+    let (_, searched) = search_files(&files, "rust", &compiled, &options, &stop, &|_, _| {
+        thread::sleep(Duration::from_millis(20))
+    });
+    assert_eq!(searched, 1);
+    assert_eq!(stop.status(), SearchStatus::TimedOut);
+
+    // Spent or cancelled once all the files are searched, the search is complete
+    let stop = StopCheck::new(&cancel, &options);
+    let (_, searched) = search_files(&files[..1], "rust", &compiled, &options, &stop, &|_, _| {
+        thread::sleep(Duration::from_millis(20));
+        cancel.cancel();
+    });
+    assert_eq!(searched, 1);
+    assert_eq!(stop.status(), SearchStatus::Complete);
+
+    options.time_budget_ms = 0;
+    let res = search_in_dir_parallel(&dir, "rust", &options, &cancel).unwrap();
+    assert_eq!(res.status, SearchStatus::Cancelled);
+    assert!(res.results.is_empty());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,565 @@
+use chrono::NaiveDate;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+
+use crate::fs_file::FileInfo;
+use crate::search_html::TextField;
+use crate::search_markdown::FRONT_MATTER_PREFIX;
+use crate::search_match::{build_matches, render_matches};
+use crate::search_normalize::{find_plain_spans, PlainMatchOptions};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DateCompare {
+    Before,
+    BeforeOrOn,
+    On,
+    OnOrAfter,
+    After,
+}
+
+// The matcher tree of a query such as `rust AND async -draft "error handling" ext:md path:journal/`
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryNode {
+    Term(String),
+    Phrase(String),
+    Ext(String),
+    Path(String),
+    Modified(DateCompare, NaiveDate),
+    // A term inside the fields of the extracted text, such as `heading:intro` or `fm.tags:rust`
+    Field(String, String),
+    And(Vec<QueryNode>),
+    Or(Vec<QueryNode>),
+    Not(Box<QueryNode>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Lexeme {
+    Word(String),
+    Quoted(String),
+    Field(String, String),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+const FIELD_EXT: &str = "ext";
+const FIELD_PATH: &str = "path";
+const FIELD_MODIFIED: &str = "modified";
+// `tag:` is short for `fm.tags:`
+const FIELD_TAG: &str = "tag";
+const FIELD_FRONT_MATTER: &str = "fm";
+const TEXT_FIELDS: [&str; 5] = ["heading", "link", "url", "code", FIELD_FRONT_MATTER];
+
+fn is_field_name(field: &str) -> bool {
+    [FIELD_EXT, FIELD_PATH, FIELD_MODIFIED, FIELD_TAG].contains(&field)
+        || TEXT_FIELDS.contains(&field)
+        || field.starts_with(FRONT_MATTER_PREFIX)
+}
+
+fn query_error(msg: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, format!("query error: {}", msg))
+}
+
+fn read_quoted(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut s = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => break,
+            '\\' => {
+                if let Some(n) = chars.next() {
+                    s.push(n);
+                }
+            }
+            _ => s.push(c),
+        }
+    }
+    s
+}
+
+fn lex(input: &str) -> Vec<Lexeme> {
+    let mut lexemes: Vec<Lexeme> = Vec::new();
+    let mut chars = input.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        match c {
+            '(' => {
+                chars.next();
+                lexemes.push(Lexeme::Open);
+                continue;
+            }
+            ')' => {
+                chars.next();
+                lexemes.push(Lexeme::Close);
+                continue;
+            }
+            '"' => {
+                chars.next();
+                lexemes.push(Lexeme::Quoted(read_quoted(&mut chars)));
+                continue;
+            }
+            '-' => {
+                chars.next();
+                match chars.peek() {
+                    Some(n) if !n.is_whitespace() && *n != ')' => lexemes.push(Lexeme::Not),
+                    _ => lexemes.push(Lexeme::Word("-".to_string())),
+                }
+                continue;
+            }
+            _ => {}
+        }
+
+        let mut word = String::new();
+        while let Some(&c) = chars.peek() {
+            if c.is_whitespace() || c == '(' || c == ')' {
+                break;
+            }
+            chars.next();
+            if c == '"' && word.ends_with(':') {
+                word.push_str(&read_quoted(&mut chars));
+                break;
+            }
+            word.push(c);
+        }
+
+        let lexeme = match word.as_str() {
+            "AND" => Lexeme::And,
+            "OR" => Lexeme::Or,
+            "NOT" => Lexeme::Not,
+            _ => match word.split_once(':') {
+                Some((field, value)) if is_field_name(field) && !value.is_empty() => {
+                    Lexeme::Field(field.to_string(), value.to_string())
+                }
+                _ => Lexeme::Word(word),
+            },
+        };
+        lexemes.push(lexeme);
+    }
+
+    lexemes
+}
+
+struct Parser {
+    lexemes: Vec<Lexeme>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Lexeme> {
+        self.lexemes.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Lexeme> {
+        let l = self.lexemes.get(self.pos).cloned();
+        self.pos += 1;
+        l
+    }
+
+    // or := and (OR and)*
+    fn parse_or(&mut self) -> Result<QueryNode, io::Error> {
+        let mut nodes = vec![self.parse_and()?];
+        while self.peek() == Some(&Lexeme::Or) {
+            self.next();
+            nodes.push(self.parse_and()?);
+        }
+
+        Ok(if nodes.len() == 1 {
+            nodes.remove(0)
+        } else {
+            QueryNode::Or(nodes)
+        })
+    }
+
+    // and := unary (AND? unary)*
+    fn parse_and(&mut self) -> Result<QueryNode, io::Error> {
+        let mut nodes = vec![self.parse_unary()?];
+        loop {
+            match self.peek() {
+                Some(Lexeme::And) => {
+                    self.next();
+                }
+                None | Some(Lexeme::Or) | Some(Lexeme::Close) => break,
+                _ => {}
+            }
+            nodes.push(self.parse_unary()?);
+        }
+
+        Ok(if nodes.len() == 1 {
+            nodes.remove(0)
+        } else {
+            QueryNode::And(nodes)
+        })
+    }
+
+    // unary := (NOT | -) unary | ( or ) | phrase | field | term
+    fn parse_unary(&mut self) -> Result<QueryNode, io::Error> {
+        match self.next() {
+            Some(Lexeme::Not) => Ok(QueryNode::Not(Box::new(self.parse_unary()?))),
+            Some(Lexeme::Open) => {
+                let node = self.parse_or()?;
+                match self.next() {
+                    Some(Lexeme::Close) => Ok(node),
+                    _ => Err(query_error("missing ')'".to_string())),
+                }
+            }
+            Some(Lexeme::Quoted(s)) => Ok(QueryNode::Phrase(s)),
+            Some(Lexeme::Word(s)) => Ok(QueryNode::Term(s)),
+            Some(Lexeme::Field(field, value)) => parse_field(&field, &value),
+            Some(l) => Err(query_error(format!("unexpected {:?}", l))),
+            None => Err(query_error("unexpected end of query".to_string())),
+        }
+    }
+}
+
+fn parse_field(field: &str, value: &str) -> Result<QueryNode, io::Error> {
+    match field {
+        FIELD_EXT => Ok(QueryNode::Ext(value.trim_start_matches('.').to_lowercase())),
+        FIELD_PATH => Ok(QueryNode::Path(value.replace('\\', "/"))),
+        FIELD_TAG => Ok(QueryNode::Field(
+            format!("{}tags", FRONT_MATTER_PREFIX),
+            value.to_string(),
+        )),
+        FIELD_MODIFIED => {
+            let (cmp, date) = if let Some(d) = value.strip_prefix(">=") {
+                (DateCompare::OnOrAfter, d)
+            } else if let Some(d) = value.strip_prefix("<=") {
+                (DateCompare::BeforeOrOn, d)
+            } else if let Some(d) = value.strip_prefix('>') {
+                (DateCompare::After, d)
+            } else if let Some(d) = value.strip_prefix('<') {
+                (DateCompare::Before, d)
+            } else {
+                (DateCompare::On, value.trim_start_matches('='))
+            };
+
+            match NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+                Ok(d) => Ok(QueryNode::Modified(cmp, d)),
+                Err(e) => Err(query_error(format!("invalid date {}: {}", date, e))),
+            }
+        }
+        _ => Ok(QueryNode::Field(field.to_lowercase(), value.to_string())),
+    }
+}
+
+// `fm` targets all the front-matter fields
+fn field_matches(query_field: &str, name: &str) -> bool {
+    name == query_field
+        || (query_field == FIELD_FRONT_MATTER && name.starts_with(FRONT_MATTER_PREFIX))
+}
+
+impl QueryNode {
+    // Evaluate the path, extension and date filters only, `None` if the text is needed
+    fn eval_file_info(&self, info: &FileInfo) -> Option<bool> {
+        match self {
+            QueryNode::Term(_) | QueryNode::Phrase(_) | QueryNode::Field(_, _) => None,
+            QueryNode::Ext(ext) => {
+                let file_ext = Path::new(&info.path)
+                    .extension()
+                    .and_then(|e| e.to_str())
+                    .unwrap_or("")
+                    .to_lowercase();
+                Some(&file_ext == ext)
+            }
+            QueryNode::Path(p) => Some(info.path.replace('\\', "/").contains(p.as_str())),
+            QueryNode::Modified(cmp, date) => {
+                let modified = info.modified.date_naive();
+                Some(match cmp {
+                    DateCompare::Before => modified < *date,
+                    DateCompare::BeforeOrOn => modified <= *date,
+                    DateCompare::On => modified == *date,
+                    DateCompare::OnOrAfter => modified >= *date,
+                    DateCompare::After => modified > *date,
+                })
+            }
+            QueryNode::And(nodes) => {
+                let mut res = Some(true);
+                for n in nodes {
+                    match n.eval_file_info(info) {
+                        Some(false) => return Some(false),
+                        None => res = None,
+                        _ => {}
+                    }
+                }
+                res
+            }
+            QueryNode::Or(nodes) => {
+                let mut res = Some(false);
+                for n in nodes {
+                    match n.eval_file_info(info) {
+                        Some(true) => return Some(true),
+                        None => res = None,
+                        _ => {}
+                    }
+                }
+                res
+            }
+            QueryNode::Not(n) => n.eval_file_info(info).map(|b| !b),
+        }
+    }
+
+    // Evaluate the whole tree, the ranges of the matched terms are pushed to `spans`
+    fn eval(
+        &self,
+        info: &FileInfo,
+        string: &str,
+        fields: &[TextField],
+        plain_match: &PlainMatchOptions,
+        spans: &mut Vec<(usize, usize)>,
+    ) -> bool {
+        match self {
+            QueryNode::Term(s) | QueryNode::Phrase(s) => {
+                let found = find_plain_spans(string, s, plain_match);
+                let matched = !found.is_empty();
+                spans.extend(found);
+                matched
+            }
+            QueryNode::Field(field, s) => {
+                let found: Vec<(usize, usize)> = find_plain_spans(string, s, plain_match)
+                    .into_iter()
+                    .filter(|(start, end)| {
+                        fields.iter().any(|f| {
+                            field_matches(field, &f.name) && f.start <= *start && *end <= f.end
+                        })
+                    })
+                    .collect();
+                let matched = !found.is_empty();
+                spans.extend(found);
+                matched
+            }
+            QueryNode::And(nodes) => {
+                let mut found: Vec<(usize, usize)> = Vec::new();
+                for n in nodes {
+                    if !n.eval(info, string, fields, plain_match, &mut found) {
+                        return false;
+                    }
+                }
+                spans.extend(found);
+                true
+            }
+            QueryNode::Or(nodes) => {
+                let mut matched = false;
+                for n in nodes {
+                    matched = n.eval(info, string, fields, plain_match, spans) || matched;
+                }
+                matched
+            }
+            QueryNode::Not(n) => !n.eval(info, string, fields, plain_match, &mut Vec::new()),
+            _ => self.eval_file_info(info).unwrap_or(false),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    pub root: QueryNode,
+}
+
+impl Query {
+    pub fn parse(input: &str) -> Result<Query, io::Error> {
+        let mut parser = Parser {
+            lexemes: lex(input),
+            pos: 0,
+        };
+        if parser.peek().is_none() {
+            return Err(query_error("empty query".to_string()));
+        }
+
+        let root = parser.parse_or()?;
+        match parser.peek() {
+            Some(l) => Err(query_error(format!("unexpected {:?}", l))),
+            None => Ok(Query { root }),
+        }
+    }
+
+    // `Some(false)` if the file can be skipped without reading it
+    pub fn eval_file_info(&self, info: &FileInfo) -> Option<bool> {
+        self.root.eval_file_info(info)
+    }
+
+    // The sorted ranges of the terms found in a matching file, `None` if the file does not
+    // match. A matching file without any positive term (e.g. `ext:md -draft`) gets an empty
+    // range at the beginning of the text, so it still has a snippet.
+    pub fn find_spans(
+        &self,
+        info: &FileInfo,
+        string: &str,
+        plain_match: &PlainMatchOptions,
+    ) -> Option<Vec<(usize, usize)>> {
+        self.find_spans_in_fields(info, string, &[], plain_match)
+    }
+
+    // `find_spans` for an extracted text, whose fields can be targeted by field terms
+    pub fn find_spans_in_fields(
+        &self,
+        info: &FileInfo,
+        string: &str,
+        fields: &[TextField],
+        plain_match: &PlainMatchOptions,
+    ) -> Option<Vec<(usize, usize)>> {
+        let mut spans: Vec<(usize, usize)> = Vec::new();
+        if !self
+            .root
+            .eval(info, string, fields, plain_match, &mut spans)
+        {
+            return None;
+        }
+
+        if spans.is_empty() {
+            return Some([(0, 0)].to_vec());
+        }
+
+        spans.sort();
+        let mut merged: Vec<(usize, usize)> = Vec::new();
+        for (start, end) in spans {
+            match merged.last_mut() {
+                Some(prev) if start < prev.1 => prev.1 = prev.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+
+        Some(merged)
+    }
+
+    // The wrapped snippets of `find_spans`, in the format of `find_matches`
+    pub fn find_matches(
+        &self,
+        info: &FileInfo,
+        string: &str,
+        plain_match: &PlainMatchOptions,
+        context_size: usize,
+        prefix: &str,
+        postfix: &str,
+    ) -> Vec<String> {
+        match self.find_spans(info, string, plain_match) {
+            Some(spans) => render_matches(
+                &build_matches(string, &spans, context_size),
+                prefix,
+                postfix,
+            ),
+            None => [].to_vec(),
+        }
+    }
+}
+
+#[test]
+fn test_parse_query() {
+    let q =
+        Query::parse(r#"rust AND async -draft "error handling" ext:md path:"my notes/""#).unwrap();
+    assert_eq!(
+        q.root,
+        QueryNode::And(
+            [
+                QueryNode::Term("rust".to_string()),
+                QueryNode::Term("async".to_string()),
+                QueryNode::Not(Box::new(QueryNode::Term("draft".to_string()))),
+                QueryNode::Phrase("error handling".to_string()),
+                QueryNode::Ext("md".to_string()),
+                QueryNode::Path("my notes/".to_string()),
+            ]
+            .to_vec()
+        )
+    );
+
+    let q = Query::parse("(a OR b) NOT c modified:>=2024-01-31").unwrap();
+    assert_eq!(
+        q.root,
+        QueryNode::And(
+            [
+                QueryNode::Or(
+                    [
+                        QueryNode::Term("a".to_string()),
+                        QueryNode::Term("b".to_string())
+                    ]
+                    .to_vec()
+                ),
+                QueryNode::Not(Box::new(QueryNode::Term("c".to_string()))),
+                QueryNode::Modified(
+                    DateCompare::OnOrAfter,
+                    NaiveDate::from_ymd_opt(2024, 1, 31).unwrap()
+                ),
+            ]
+            .to_vec()
+        )
+    );
+
+    assert!(Query::parse("(a OR b").is_err());
+    assert!(Query::parse("modified:2024-13-01").is_err());
+    assert!(Query::parse("").is_err());
+}
+
+#[test]
+fn test_query_find_matches() {
+    let mut info = FileInfo::new();
+    info.path = "/notes/journal/2024.md".to_string();
+    let plain_match = PlainMatchOptions::new();
+    let string = "rust async and error handling";
+
+    let q = Query::parse(r#"rust async -draft "error handling" ext:md path:journal/"#).unwrap();
+    assert_eq!(q.eval_file_info(&info), None);
+    assert_eq!(
+        q.find_matches(&info, string, &plain_match, 4, "<b>", "</b>"),
+        [
+            "<b>rust</b> asy",
+            "ust <b>async</b> and",
+            "and <b>error handling</b>"
+        ]
+    );
+
+    let q = Query::parse("rust -async").unwrap();
+    assert!(q
+        .find_matches(&info, string, &plain_match, 4, "<b>", "</b>")
+        .is_empty());
+
+    let q = Query::parse("ext:txt OR path:work/").unwrap();
+    assert_eq!(q.eval_file_info(&info), Some(false));
+
+    let q = Query::parse("ext:md -draft").unwrap();
+    assert_eq!(
+        q.find_matches(&info, string, &plain_match, 4, "<b>", "</b>"),
+        ["rust"]
+    );
+}
+
+#[test]
+fn test_query_fields() {
+    let q = Query::parse("heading:intro tag:rust fm:draft").unwrap();
+    assert_eq!(
+        q.root,
+        QueryNode::And(
+            [
+                QueryNode::Field("heading".to_string(), "intro".to_string()),
+                QueryNode::Field("fm.tags".to_string(), "rust".to_string()),
+                QueryNode::Field("fm".to_string(), "draft".to_string()),
+            ]
+            .to_vec()
+        )
+    );
+
+    let string = "rust\nIntro to rust";
+    let field = |name: &str, start: usize, end: usize| TextField {
+        name: name.to_string(),
+        start,
+        end,
+    };
+    let fields = [field("fm.tags", 0, 4), field("heading", 5, 18)];
+    let info = FileInfo::new();
+    let mut plain_match = PlainMatchOptions::new();
+    plain_match.ignore_case = true;
+
+    let q = Query::parse("heading:rust tag:rust").unwrap();
+    assert_eq!(
+        q.find_spans_in_fields(&info, string, &fields, &plain_match),
+        Some([(0, 4), (14, 18)].to_vec())
+    );
+    let q = Query::parse("fm:intro").unwrap();
+    assert_eq!(
+        q.find_spans_in_fields(&info, string, &fields, &plain_match),
+        None
+    );
+    assert_eq!(q.find_spans(&info, string, &plain_match), None);
+}
@@ -0,0 +1,202 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::fs_file;
+use crate::search::SearchFileRes;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RankOptions {
+    // BM25 term frequency saturation and length normalization
+    pub k1: f64,
+    pub b: f64,
+    // Extra weight when the file name matches the search, 1.0 doubles the score
+    pub filename_boost: f64,
+    // Extra weight of a file modified just now, halved every `recency_half_life_days`
+    pub recency_boost: f64,
+    pub recency_half_life_days: f64,
+    // Sort the results by score, best first, instead of the directory walk order
+    pub sort: bool,
+    // Keep only the best N results, sorted by score even if `sort` is false, 0 for all
+    pub top_n: usize,
+}
+
+impl Default for RankOptions {
+    fn default() -> Self {
+        RankOptions::new()
+    }
+}
+
+impl RankOptions {
+    pub fn new() -> RankOptions {
+        RankOptions {
+            k1: 1.2,
+            b: 0.75,
+            filename_boost: 1.0,
+            recency_boost: 0.5,
+            recency_half_life_days: 30.0,
+            sort: false,
+            top_n: 0,
+        }
+    }
+}
+
+// Text lengths of the searched files, collected while searching
+#[derive(Debug, Default)]
+pub struct CorpusStats {
+    pub file_count: usize,
+    pub total_len: usize,
+    text_lens: HashMap<String, usize>,
+}
+
+impl CorpusStats {
+    pub fn add_file(&mut self, file_path: &str, text_len: usize, is_matched: bool) {
+        self.file_count += 1;
+        self.total_len += text_len;
+        if is_matched {
+            self.text_lens.insert(file_path.to_string(), text_len);
+        }
+    }
+
+    // Add the stats collected by another worker
+    pub fn merge(&mut self, other: CorpusStats) {
+        self.file_count += other.file_count;
+        self.total_len += other.total_len;
+        self.text_lens.extend(other.text_lens);
+    }
+
+    pub fn avg_len(&self) -> f64 {
+        if self.file_count == 0 {
+            return 0.0;
+        }
+        self.total_len as f64 / self.file_count as f64
+    }
+}
+
+pub fn bm25(tf: f64, df: f64, file_count: f64, len: f64, avg_len: f64, k1: f64, b: f64) -> f64 {
+    let idf = (1.0 + (file_count - df + 0.5) / (df + 0.5)).ln();
+    let norm = if avg_len > 0.0 {
+        1.0 - b + b * len / avg_len
+    } else {
+        1.0
+    };
+
+    idf * tf * (k1 + 1.0) / (tf + k1 * norm)
+}
+
+fn recency_factor(file_path: &str, options: &RankOptions) -> f64 {
+    if options.recency_boost <= 0.0 || options.recency_half_life_days <= 0.0 {
+        return 1.0;
+    }
+
+    let age_days = match SystemTime::now().duration_since(fs_file::get_modified(file_path)) {
+        Ok(d) => d.as_secs_f64() / 86400.0,
+        Err(_) => 0.0,
+    };
+
+    1.0 + options.recency_boost * 0.5_f64.powf(age_days / options.recency_half_life_days)
+}
+
+// Set the score of every result: BM25 of the matches as terms, weighted by the match score,
+// the file name match and how recently the file was modified. Then sort and truncate the
+// results if required.
+pub fn rank_results(
+    results: &mut Vec<SearchFileRes>,
+    stats: &CorpusStats,
+    name_matches: &dyn Fn(&str) -> bool,
+    options: &RankOptions,
+) {
+    let df = results.len() as f64;
+    let file_count = stats.file_count.max(results.len()) as f64;
+    let avg_len = stats.avg_len();
+
+    for res in results.iter_mut() {
+        let len = match stats.text_lens.get(&res.path) {
+            Some(l) => *l as f64,
+            None => avg_len,
+        };
+        // `matches` are blocks of lines in the line context mode, `positions` are single matches
+        let mut score = bm25(
+            res.positions.len().max(res.matches.len()) as f64,
+            df,
+            file_count,
+            len,
+            avg_len,
+            options.k1,
+            options.b,
+        );
+        score *= res.match_score as f64;
+
+        let file_name = Path::new(&res.path)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("");
+        if name_matches(file_name) {
+            score *= 1.0 + options.filename_boost;
+        }
+
+        score *= recency_factor(&res.path, options);
+        res.score = score;
+    }
+
+    if options.sort || options.top_n > 0 {
+        results.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal));
+    }
+    if options.top_n > 0 {
+        results.truncate(options.top_n);
+    }
+}
+
+#[test]
+fn test_rank_results() {
+    let res = |path: &str, count: usize| SearchFileRes {
+        path: path.to_string(),
+        matches: vec!["".to_string(); count],
+        match_score: 1.0,
+        score: 0.0,
+        positions: [].to_vec(),
+        line_contexts: [].to_vec(),
+    };
+    let mut results = [
+        res("/notes/a.md", 1),
+        res("/notes/b.md", 5),
+        res("/notes/rust.md", 1),
+    ]
+    .to_vec();
+
+    let mut stats = CorpusStats::default();
+    stats.add_file("/notes/a.md", 100, true);
+    stats.add_file("/notes/b.md", 100, true);
+    stats.add_file("/notes/rust.md", 100, true);
+    stats.add_file("/notes/other.md", 100, false);
+
+    let mut options = RankOptions::new();
+    options.sort = true;
+    options.top_n = 2;
+    rank_results(
+        &mut results,
+        &stats,
+        &|name| name.contains("rust"),
+        &options,
+    );
+
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0].path, "/notes/rust.md");
+    assert_eq!(results[1].path, "/notes/b.md");
+    assert!(results[0].score > results[1].score);
+
+    // The best result, not the first one walked
+    let mut results = [
+        res("/notes/a.md", 1),
+        res("/notes/b.md", 6),
+        res("/notes/c.md", 1),
+    ]
+    .to_vec();
+    options.sort = false;
+    options.top_n = 1;
+    rank_results(&mut results, &stats, &|_| false, &options);
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].path, "/notes/b.md");
+}
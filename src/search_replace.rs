@@ -0,0 +1,456 @@
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::datetime::current_time_ymdhms;
+use crate::fs::path_buf_to_string;
+use crate::fs_encoding::{decode_text_strict, encode_text, has_bom};
+use crate::fs_file;
+use crate::hash::sha256_by_bytes;
+use crate::search::{compile_search, CompiledSearch, SearchMode, SearchOptions};
+use crate::search_filter::DirWalker;
+use crate::search_normalize::find_plain_spans;
+
+const JOURNAL_FILE_NAME: &str = "journal.json";
+
+// The lines touched by some replacements, before and after
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReplaceChange {
+    // First line of `before`, starts from 1
+    pub line: usize,
+    pub before: String,
+    pub after: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileReplace {
+    pub path: String,
+    pub replacements: usize,
+    pub changes: Vec<ReplaceChange>,
+    // Hash of the raw content the preview was made from, `apply_replace` refuses to touch the
+    // file if it changed since
+    pub sha256: String,
+    // Encoding the file was read in, the replaced text is written back in it, with its BOM
+    #[serde(default)]
+    pub encoding: String,
+}
+
+// The preview of a replace, pass it to `apply_replace` once confirmed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReplacePlan {
+    pub search: String,
+    pub replacement: String,
+    pub options: SearchOptions,
+    pub files: Vec<FileReplace>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JournalEntry {
+    pub path: String,
+    // File name of the original content, inside the journal dir
+    pub backup: String,
+    pub sha256_before: String,
+    pub sha256_after: String,
+}
+
+// Written before any file is replaced, `undo_replace` restores the backups listed in it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReplaceJournal {
+    pub dir_path: String,
+    pub created_at: String,
+    pub search: String,
+    pub replacement: String,
+    pub files: Vec<JournalEntry>,
+}
+
+fn replace_spans(
+    text: &str,
+    search: &str,
+    replacement: &str,
+    compiled: &CompiledSearch,
+    options: &SearchOptions,
+) -> Vec<(usize, usize, String)> {
+    match &compiled.re {
+        // `$1` and `${name}` in the replacement refer to the capture groups
+        Some(re) => re
+            .captures_iter(text)
+            .filter_map(|caps| {
+                let m = caps.get(0)?;
+                let mut dst = String::new();
+                caps.expand(replacement, &mut dst);
+                Some((m.start(), m.end(), dst))
+            })
+            .collect(),
+        None => find_plain_spans(text, search, &options.plain_match)
+            .into_iter()
+            .map(|(start, end)| (start, end, replacement.to_string()))
+            .collect(),
+    }
+}
+
+fn splice(text: &str, start: usize, end: usize, spans: &[(usize, usize, String)]) -> String {
+    let mut res = String::new();
+    let mut last = start;
+    for (s, e, r) in spans {
+        res.push_str(&text[last..*s]);
+        res.push_str(r);
+        last = *e;
+    }
+    res.push_str(&text[last..end]);
+    res
+}
+
+// Group the replacements by the lines they touch
+fn build_changes(text: &str, spans: &[(usize, usize, String)]) -> Vec<ReplaceChange> {
+    let line_start = |pos: usize| text[..pos].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = |pos: usize| text[pos..].find('\n').map_or(text.len(), |i| pos + i);
+
+    let mut groups: Vec<(usize, usize, usize, usize)> = Vec::new();
+    for (i, (s, e, _)) in spans.iter().enumerate() {
+        let (gs, ge) = (line_start(*s), line_end(*e));
+        match groups.last_mut() {
+            Some(last) if gs <= last.1 => {
+                last.1 = last.1.max(ge);
+                last.3 = i + 1;
+            }
+            _ => groups.push((gs, ge, i, i + 1)),
+        }
+    }
+
+    let mut line = 1;
+    let mut counted = 0;
+    groups
+        .into_iter()
+        .map(|(gs, ge, first, last)| {
+            line += text[counted..gs].matches('\n').count();
+            counted = gs;
+            ReplaceChange {
+                line,
+                before: text[gs..ge].to_string(),
+                after: splice(text, gs, ge, &spans[first..last]),
+            }
+        })
+        .collect()
+}
+
+// The raw content of a file and its text decoded with the detected encoding. Fails if some
+// bytes are malformed in that encoding, they would be replaced when writing the file back.
+fn read_text(path: &Path) -> Result<(Vec<u8>, String, &'static str), io::Error> {
+    let bytes = fs::read(path)?;
+    let (text, encoding) = decode_text_strict(&bytes)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    Ok((bytes, text, encoding))
+}
+
+fn check_mode(options: &SearchOptions) -> Result<(), io::Error> {
+    match options.mode {
+        SearchMode::Plain | SearchMode::Regex => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "replace only supports the plain and regex modes",
+        )),
+    }
+}
+
+// Replace all the matches of `search` in `text`, returns the new text and the count of
+// replacements. Only the plain and regex modes are supported.
+pub fn replace_in_text(
+    text: &str,
+    search: &str,
+    replacement: &str,
+    options: &SearchOptions,
+) -> Result<(String, usize), io::Error> {
+    check_mode(options)?;
+    let compiled = compile_search(search, options)?;
+    let spans = replace_spans(text, search, replacement, &compiled, options);
+
+    Ok((splice(text, 0, text.len(), &spans), spans.len()))
+}
+
+// Find what replacing `search` with `replacement` would change in the files of `dir_path`,
+// without writing anything. The raw file content is replaced, so html like files
+// (`options.html_like_exts`) are skipped.
+pub fn preview_replace_in_dir(
+    dir_path: &Path,
+    search: &str,
+    replacement: &str,
+    options: &SearchOptions,
+) -> Result<ReplacePlan, io::Error> {
+    check_mode(options)?;
+    let compiled = compile_search(search, options)?;
+    let mut files: Vec<FileReplace> = Vec::new();
+
+    for path in DirWalker::new(dir_path, &options.filter)?.walk(&|| false)? {
+        let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        if !ext.is_empty() && options.html_like_exts.iter().any(|e| e == ext) {
+            continue;
+        }
+
+        let (bytes, text, encoding) = match read_text(&path) {
+            Ok(t) => t,
+            Err(e) => {
+                debug!("read file error: {}", e);
+                continue;
+            }
+        };
+        let spans = replace_spans(&text, search, replacement, &compiled, options);
+        if spans.is_empty() {
+            continue;
+        }
+
+        files.push(FileReplace {
+            path: path_buf_to_string(path),
+            replacements: spans.len(),
+            changes: build_changes(&text, &spans),
+            sha256: sha256_by_bytes(&bytes),
+            encoding: encoding.to_string(),
+        });
+    }
+
+    Ok(ReplacePlan {
+        search: search.to_string(),
+        replacement: replacement.to_string(),
+        options: options.clone(),
+        files,
+    })
+}
+
+fn create_journal_dir(journal_root: &Path) -> Result<PathBuf, io::Error> {
+    let name = current_time_ymdhms("%Y%m%d%H%M%S%3f");
+    let mut dir = journal_root.join(&name);
+    let mut n = 1;
+    while dir.exists() {
+        dir = journal_root.join(format!("{}-{}", name, n));
+        n += 1;
+    }
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+fn write_atomic(path: &str, content: &[u8]) -> Result<(), io::Error> {
+    fs_file::write_bytes_atomic(path, content).map_err(|e| io::Error::other(e.to_string()))
+}
+
+// Apply a plan made by `preview_replace_in_dir`. Nothing is written if any of the files
+// changed since the preview. The original contents are backed up into a new journal dir
+// inside `journal_root` first, then every file is replaced atomically; if one of them fails
+// the files already replaced are restored.
+pub fn apply_replace(plan: &ReplacePlan, journal_root: &Path) -> Result<ReplaceJournal, io::Error> {
+    check_mode(&plan.options)?;
+    let compiled = compile_search(&plan.search, &plan.options)?;
+
+    let mut replaced: Vec<(String, Vec<u8>, Vec<u8>)> = Vec::new();
+    for file in &plan.files {
+        let (bytes, text, encoding) = read_text(Path::new(&file.path))?;
+        if sha256_by_bytes(&bytes) != file.sha256 {
+            return Err(io::Error::other(format!(
+                "file changed since the preview: {}",
+                file.path
+            )));
+        }
+        let spans = replace_spans(
+            &text,
+            &plan.search,
+            &plan.replacement,
+            &compiled,
+            &plan.options,
+        );
+        let new_text = splice(&text, 0, text.len(), &spans);
+        let new_bytes = encode_text(&new_text, encoding, has_bom(&bytes))
+            .map_err(|e| io::Error::new(e.kind(), format!("replace {} error: {}", file.path, e)))?;
+        replaced.push((file.path.clone(), bytes, new_bytes));
+    }
+
+    let dir = create_journal_dir(journal_root)?;
+    let mut journal = ReplaceJournal {
+        dir_path: path_buf_to_string(dir.clone()),
+        created_at: current_time_ymdhms(""),
+        search: plan.search.clone(),
+        replacement: plan.replacement.clone(),
+        files: Vec::new(),
+    };
+    for (i, (path, bytes, new_bytes)) in replaced.iter().enumerate() {
+        let backup = format!("{:05}.bak", i + 1);
+        fs::write(dir.join(&backup), bytes)?;
+        journal.files.push(JournalEntry {
+            path: path.clone(),
+            backup,
+            sha256_before: sha256_by_bytes(bytes),
+            sha256_after: sha256_by_bytes(new_bytes),
+        });
+    }
+    let journal_json = serde_json::to_string_pretty(&journal)?;
+    write_atomic(
+        &path_buf_to_string(dir.join(JOURNAL_FILE_NAME)),
+        journal_json.as_bytes(),
+    )?;
+
+    for (i, (path, _, new_bytes)) in replaced.iter().enumerate() {
+        if let Err(e) = write_atomic(path, new_bytes) {
+            for (path, bytes, _) in &replaced[..i] {
+                if let Err(e) = write_atomic(path, bytes) {
+                    debug!("restore file error: {}", e);
+                }
+            }
+            return Err(io::Error::other(format!("replace {} error: {}", path, e)));
+        }
+    }
+
+    Ok(journal)
+}
+
+pub fn read_journal(journal_dir: &Path) -> Result<ReplaceJournal, io::Error> {
+    let json = fs::read_to_string(journal_dir.join(JOURNAL_FILE_NAME))?;
+    Ok(serde_json::from_str(&json)?)
+}
+
+// Restore the files replaced by `apply_replace` from the backups of its journal dir.
+// Nothing is restored if any of the files changed since the replace.
+pub fn undo_replace(journal_dir: &Path) -> Result<ReplaceJournal, io::Error> {
+    let journal = read_journal(journal_dir)?;
+
+    let mut backups: Vec<(&str, Vec<u8>)> = Vec::new();
+    for entry in &journal.files {
+        let current = fs::read(&entry.path)?;
+        if sha256_by_bytes(&current) != entry.sha256_after {
+            return Err(io::Error::other(format!(
+                "file changed since the replace: {}",
+                entry.path
+            )));
+        }
+        backups.push((&entry.path, fs::read(journal_dir.join(&entry.backup))?));
+    }
+
+    for (path, content) in backups {
+        write_atomic(path, &content)?;
+    }
+
+    Ok(journal)
+}
+
+#[test]
+fn test_replace_in_text() {
+    let mut options = SearchOptions::new();
+    options.mode = SearchMode::Regex;
+    let (text, count) = replace_in_text(
+        "date: 2024-01-31",
+        r"(\d+)-(\d+)-(\d+)",
+        "$3/$2/$1",
+        &options,
+    )
+    .unwrap();
+    assert_eq!((text.as_str(), count), ("date: 31/01/2024", 1));
+
+    let spans = [(6, 9, "x".to_string()), (10, 13, "y".to_string())];
+    let changes = build_changes("line1\nfoo bar\nline3", &spans);
+    assert_eq!(
+        changes,
+        [ReplaceChange {
+            line: 2,
+            before: "foo bar".to_string(),
+            after: "x y".to_string()
+        }]
+    );
+
+    options.mode = SearchMode::Fuzzy;
+    assert!(replace_in_text("a", "a", "b", &options).is_err());
+}
+
+#[test]
+fn test_apply_and_undo_replace() {
+    let dir = std::env::temp_dir().join("fivim_rs_utils_test_search_replace");
+    let _ = fs::remove_dir_all(&dir);
+    let notes = dir.join("notes");
+    let a = path_buf_to_string(notes.join("a.md"));
+    let b = path_buf_to_string(notes.join("b.md"));
+    let _ = fs_file::write_str(&a, "# Rust\nrust is fast\n");
+    let _ = fs_file::write_str(&b, "nothing here\n");
+
+    let mut options = SearchOptions::new();
+    options.plain_match.ignore_case = true;
+    let plan = preview_replace_in_dir(&notes, "rust", "Ferris", &options).unwrap();
+    assert_eq!(plan.files.len(), 1);
+    assert_eq!(plan.files[0].replacements, 2);
+    assert_eq!(plan.files[0].changes.len(), 2);
+    assert_eq!(plan.files[0].changes[1].line, 2);
+    assert_eq!(plan.files[0].changes[1].after, "Ferris is fast");
+    assert_eq!(
+        fs::read_to_string(&a).unwrap(),
+        "# Rust\nrust is fast\n",
+        "preview must not write"
+    );
+
+    let journal = apply_replace(&plan, &dir.join("journal")).unwrap();
+    assert_eq!(
+        fs::read_to_string(&a).unwrap(),
+        "# Ferris\nFerris is fast\n"
+    );
+    assert!(apply_replace(&plan, &dir.join("journal")).is_err());
+
+    undo_replace(Path::new(&journal.dir_path)).unwrap();
+    assert_eq!(fs::read_to_string(&a).unwrap(), "# Rust\nrust is fast\n");
+    assert!(undo_replace(Path::new(&journal.dir_path)).is_err());
+
+    // Files are written back in their encoding, next to an untouched `.tmp` file and with
+    // their permissions
+    let gbk = notes.join("gbk.txt");
+    let _ = fs::write(&gbk, encode_text("中文 rust", "gbk", false).unwrap());
+    let utf16 = notes.join("utf16.txt");
+    let _ = fs::write(&utf16, encode_text("rust", "UTF-16LE", true).unwrap());
+    let _ = fs_file::write_str(&format!("{}.tmp", a), "keep me");
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let _ = fs::set_permissions(&a, fs::Permissions::from_mode(0o640));
+    }
+    let plan = preview_replace_in_dir(&notes, "rust", "Ferris", &options).unwrap();
+    assert_eq!(plan.files.len(), 3);
+    apply_replace(&plan, &dir.join("journal")).unwrap();
+    assert_eq!(
+        fs::read(&gbk).unwrap(),
+        encode_text("中文 Ferris", "gbk", false).unwrap()
+    );
+    assert_eq!(
+        fs::read(&utf16).unwrap(),
+        encode_text("Ferris", "UTF-16LE", true).unwrap()
+    );
+    // Nothing is written when the replacement can not be encoded
+    let plan = preview_replace_in_dir(&notes, "Ferris", "😀", &options).unwrap();
+    assert!(apply_replace(&plan, &dir.join("journal")).is_err());
+    assert_eq!(
+        fs::read(&gbk).unwrap(),
+        encode_text("中文 Ferris", "gbk", false).unwrap()
+    );
+    assert_eq!(fs::read_to_string(format!("{}.tmp", a)).unwrap(), "keep me");
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&a).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+    }
+    let names: Vec<String> = fs::read_dir(&notes)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    assert_eq!(names.len(), 5);
+
+    // A file in an encoding that is not detected, like Latin-1, is never rewritten
+    let latin1 = notes.join("latin1.txt");
+    let _ = fs::write(&latin1, b"caf\xe9 rust");
+    let plan = preview_replace_in_dir(&notes, "rust", "ferris", &options).unwrap();
+    assert!(plan.files.is_empty());
+    let mut forged = plan.clone();
+    forged.files.push(FileReplace {
+        path: path_buf_to_string(latin1.clone()),
+        replacements: 1,
+        changes: [].to_vec(),
+        sha256: sha256_by_bytes(b"caf\xe9 rust"),
+        encoding: "UTF-8".to_string(),
+    });
+    assert!(apply_replace(&forged, &dir.join("journal")).is_err());
+    assert_eq!(fs::read(&latin1).unwrap(), b"caf\xe9 rust");
+
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,247 @@
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::io;
+use std::path::PathBuf;
+
+use crate::datetime::current_time_ymdhms;
+use crate::fs_file;
+use crate::json_toml::{json_to_toml, toml_to_json};
+use crate::search::{search_in_dir_with_options, SearchFileRes, SearchOptions};
+
+const DEFAULT_HISTORY_LIMIT: usize = 50;
+
+// A named search, like a smart folder
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SavedSearch {
+    pub name: String,
+    pub dir_path: String,
+    pub search: String,
+    pub options: SearchOptions,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchHistoryEntry {
+    pub dir_path: String,
+    pub search: String,
+    pub options: SearchOptions,
+    pub searched_at: String,
+    // Matched files of the last run
+    pub result_count: usize,
+}
+
+// Saved searches and recent searches persisted to a JSON file, or a TOML file if the path
+// ends with `.toml`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchStore {
+    #[serde(skip)]
+    file_path: String,
+    #[serde(default)]
+    pub saved: Vec<SavedSearch>,
+    // Most recent first, without duplicates
+    #[serde(default)]
+    pub history: Vec<SearchHistoryEntry>,
+    #[serde(default = "default_history_limit")]
+    pub history_limit: usize,
+}
+
+fn default_history_limit() -> usize {
+    DEFAULT_HISTORY_LIMIT
+}
+
+fn is_toml(file_path: &str) -> bool {
+    file_path.to_lowercase().ends_with(".toml")
+}
+
+impl SearchStore {
+    // A missing file gives an empty store, created on the first `save`
+    pub fn load(file_path: &str) -> Result<SearchStore, Box<dyn Error>> {
+        let content = fs_file::read_to_string(file_path)?;
+        let mut store: SearchStore = if content.trim().is_empty() {
+            SearchStore {
+                file_path: "".to_string(),
+                saved: [].to_vec(),
+                history: [].to_vec(),
+                history_limit: DEFAULT_HISTORY_LIMIT,
+            }
+        } else if is_toml(file_path) {
+            serde_json::from_str(&toml_to_json(&content)?)?
+        } else {
+            serde_json::from_str(&content)?
+        };
+
+        store.file_path = file_path.to_string();
+        Ok(store)
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let json = serde_json::to_string_pretty(self)?;
+        let content = if is_toml(&self.file_path) {
+            json_to_toml(&json)?
+        } else {
+            json
+        };
+
+        fs_file::write_bytes_atomic(&self.file_path, content.as_bytes())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&SavedSearch> {
+        self.saved.iter().find(|s| s.name == name)
+    }
+
+    // Add a named search or replace the one with the same name
+    pub fn save_search(
+        &mut self,
+        name: &str,
+        dir_path: &str,
+        search: &str,
+        options: &SearchOptions,
+    ) {
+        let now = current_time_ymdhms("");
+        match self.saved.iter_mut().find(|s| s.name == name) {
+            Some(s) => {
+                s.dir_path = dir_path.to_string();
+                s.search = search.to_string();
+                s.options = options.clone();
+                s.updated_at = now;
+            }
+            None => self.saved.push(SavedSearch {
+                name: name.to_string(),
+                dir_path: dir_path.to_string(),
+                search: search.to_string(),
+                options: options.clone(),
+                created_at: now.clone(),
+                updated_at: now,
+            }),
+        }
+    }
+
+    pub fn remove_search(&mut self, name: &str) -> bool {
+        let len = self.saved.len();
+        self.saved.retain(|s| s.name != name);
+        self.saved.len() != len
+    }
+
+    pub fn rename_search(&mut self, name: &str, new_name: &str) -> bool {
+        if self.get(new_name).is_some() {
+            return false;
+        }
+        match self.saved.iter_mut().find(|s| s.name == name) {
+            Some(s) => {
+                s.name = new_name.to_string();
+                s.updated_at = current_time_ymdhms("");
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Record a search at the top of the history, an older run of the same search in the same
+    // directory is removed
+    pub fn add_history(
+        &mut self,
+        dir_path: &str,
+        search: &str,
+        options: &SearchOptions,
+        result_count: usize,
+    ) {
+        self.history
+            .retain(|h| !(h.dir_path == dir_path && h.search == search));
+        self.history.insert(
+            0,
+            SearchHistoryEntry {
+                dir_path: dir_path.to_string(),
+                search: search.to_string(),
+                options: options.clone(),
+                searched_at: current_time_ymdhms(""),
+                result_count,
+            },
+        );
+        self.history.truncate(self.history_limit);
+    }
+
+    pub fn clear_history(&mut self) {
+        self.history.clear();
+    }
+
+    // Search with a saved search and add it to the history, the store is not saved
+    pub fn run_saved(&mut self, name: &str) -> Result<Vec<SearchFileRes>, io::Error> {
+        let saved = match self.get(name) {
+            Some(s) => s.clone(),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("saved search not found: {}", name),
+                ))
+            }
+        };
+
+        let results = search_in_dir_with_options(
+            &PathBuf::from(&saved.dir_path),
+            &saved.search,
+            &saved.options,
+        )?;
+        self.add_history(
+            &saved.dir_path,
+            &saved.search,
+            &saved.options,
+            results.len(),
+        );
+        Ok(results)
+    }
+}
+
+#[test]
+fn test_search_store() {
+    use crate::fs::path_buf_to_string;
+    use crate::search::SearchMode;
+
+    let dir = std::env::temp_dir().join("fivim_rs_utils_test_search_saved");
+    let _ = std::fs::remove_dir_all(&dir);
+    let notes = dir.join("notes");
+    let _ = fs_file::write_str(&path_buf_to_string(notes.join("a.md")), "rust notes");
+    let _ = fs_file::write_str(&path_buf_to_string(notes.join("b.md")), "go notes");
+
+    for name in ["searches.json", "searches.toml"] {
+        let store_path = path_buf_to_string(dir.join(name));
+        let mut store = SearchStore::load(&store_path).unwrap();
+        assert!(store.saved.is_empty());
+
+        let mut options = SearchOptions::new();
+        options.mode = SearchMode::Regex;
+        options.context_lines = Some(1);
+        store.save_search("rust", &path_buf_to_string(notes.clone()), "ru.t", &options);
+        store.save_search(
+            "all",
+            &path_buf_to_string(notes.clone()),
+            "notes",
+            &SearchOptions::new(),
+        );
+        assert!(store.rename_search("all", "notes"));
+        assert!(!store.rename_search("notes", "rust"));
+        store.save().unwrap();
+
+        let mut store = SearchStore::load(&store_path).unwrap();
+        assert_eq!(store.saved.len(), 2);
+        assert_eq!(store.get("rust").unwrap().options.mode, SearchMode::Regex);
+        assert_eq!(store.get("rust").unwrap().options.context_lines, Some(1));
+
+        assert_eq!(store.run_saved("rust").unwrap().len(), 1);
+        assert_eq!(store.run_saved("notes").unwrap().len(), 2);
+        assert_eq!(store.run_saved("rust").unwrap().len(), 1);
+        assert!(store.run_saved("nope").is_err());
+        assert_eq!(store.history.len(), 2);
+        assert_eq!(store.history[0].search, "ru.t");
+
+        store.history_limit = 1;
+        store.add_history("/tmp", "x", &SearchOptions::new(), 0);
+        assert_eq!(store.history.len(), 1);
+        assert!(store.remove_search("rust"));
+        store.save().unwrap();
+        let store = SearchStore::load(&store_path).unwrap();
+        assert_eq!((store.saved.len(), store.history.len()), (1, 1));
+    }
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
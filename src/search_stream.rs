@@ -0,0 +1,127 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use tokio::sync::mpsc;
+
+use crate::progress as xu_progress;
+use crate::search::{compile_search, SearchCallback, SearchFileRes, SearchOptions};
+use crate::search_parallel::{search_files, walk_files, CancelToken, SearchStatus, StopCheck};
+
+// Like `search_in_dir_parallel`, but each result is passed to `on_result` as soon as its file
+// matched instead of being returned at the end. The results are not ranked, since the scores
+// need the whole corpus, and arrive in no particular order.
+// Files are searched like `search_in_dir_with_options`, archives included when
+// `options.search_archives` is set. When `progress_name` is not empty, the share of searched
+// files, unreadable ones included, and the current file path are reported through the
+// `progress` module.
+pub fn search_in_dir_streaming(
+    dir_path: &Path,
+    search: &str,
+    options: &SearchOptions,
+    cancel: &CancelToken,
+    on_result: &SearchCallback,
+    progress_name: &str,
+) -> Result<SearchStatus, io::Error> {
+    let compiled = compile_search(search, options)?;
+    let stop = StopCheck::new(cancel, options);
+    if !progress_name.is_empty() {
+        xu_progress::insert_new(progress_name);
+    }
+    let files = walk_files(dir_path, options, &stop)?;
+
+    let done = AtomicUsize::new(0);
+    search_files(&files, search, &compiled, options, &stop, &|i, res| {
+        if !progress_name.is_empty() {
+            let pct = (done.fetch_add(1, Ordering::SeqCst) + 1) as f32 / files.len() as f32;
+            xu_progress::set(progress_name, pct, &files[i].to_string_lossy());
+        }
+        for res in res {
+            on_result(res);
+        }
+    });
+
+    if !progress_name.is_empty() {
+        xu_progress::set(progress_name, 1.0, "");
+    }
+    Ok(stop.status())
+}
+
+// Run `search_in_dir_streaming` on a new thread and receive the results from a channel,
+// which is closed once the search is over. The handle returns the status of the search.
+// Dropping the receiver does not stop the search, cancel the token for that.
+pub fn search_in_dir_channel(
+    dir_path: PathBuf,
+    search: String,
+    options: SearchOptions,
+    cancel: CancelToken,
+    progress_name: String,
+) -> (
+    mpsc::Receiver<SearchFileRes>,
+    thread::JoinHandle<Result<SearchStatus, io::Error>>,
+) {
+    let (tx, rx) = mpsc::channel::<SearchFileRes>(64);
+
+    let handle = thread::spawn(move || {
+        search_in_dir_streaming(
+            &dir_path,
+            &search,
+            &options,
+            &cancel,
+            &move |res| {
+                let _ = tx.blocking_send(res);
+            },
+            &progress_name,
+        )
+    });
+
+    (rx, handle)
+}
+
+#[tokio::test]
+async fn test_search_in_dir_channel() {
+    let dir = std::env::temp_dir().join("fivim_rs_utils_test_search_stream");
+    let _ = std::fs::remove_dir_all(&dir);
+    for i in 0..10 {
+        let _ = crate::fs_file::write_str(
+            &crate::fs::path_buf_to_string(dir.join(format!("{:02}.md", i))),
+            if i % 2 == 0 { "stream me" } else { "nothing" },
+        );
+    }
+    let mut writer = zip::ZipWriter::new(std::fs::File::create(dir.join("notes.zip")).unwrap());
+    writer
+        .start_file("z.md", zip::write::FileOptions::default())
+        .unwrap();
+    std::io::Write::write_all(&mut writer, b"stream me").unwrap();
+    writer.finish().unwrap();
+    // Bigger than max_memory and not plain text, so it can not be read
+    let _ = crate::fs_file::write_str(
+        &crate::fs::path_buf_to_string(dir.join("big.md")),
+        &"stream me ".repeat(100),
+    );
+
+    let mut options = SearchOptions::new();
+    options.search_archives = true;
+    options.max_memory = 100;
+    let (mut rx, handle) = search_in_dir_channel(
+        dir.clone(),
+        "stream".to_string(),
+        options,
+        CancelToken::new(),
+        "test_search_in_dir_channel".to_string(),
+    );
+    let mut paths: Vec<String> = Vec::new();
+    while let Some(res) = rx.recv().await {
+        assert_eq!(res.matches, ["<b>stream</b> me"]);
+        paths.push(res.path);
+    }
+
+    assert_eq!(handle.join().unwrap().unwrap(), SearchStatus::Complete);
+    assert_eq!(paths.len(), 6);
+    assert!(paths.iter().any(|p| p.ends_with("notes.zip!/z.md")));
+    let status = serde_json::to_value(xu_progress::get("test_search_in_dir_channel")).unwrap();
+    assert_eq!(status["percentage"], 1.0);
+    xu_progress::delete("test_search_in_dir_channel");
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
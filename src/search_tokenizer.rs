@@ -0,0 +1,389 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::fs_file;
+use crate::hash::sha256_by_bytes;
+use crate::search::wrap_text;
+
+// A normalized word of a text, `start` and `end` are byte offsets in the original text
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Token {
+    pub text: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+pub trait Tokenizer: Send + Sync {
+    fn tokenize(&self, text: &str) -> Vec<Token>;
+
+    // Identifies the tokenizer and its settings, a search index built with another one is
+    // rebuilt
+    fn name(&self) -> String;
+
+    // Whether CJK text is split into overlapping n-grams instead of words, a single character
+    // is then searched inside the tokens
+    fn is_ngram(&self) -> bool {
+        false
+    }
+}
+
+impl fmt::Debug for dyn Tokenizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Tokenizer({})", self.name())
+    }
+}
+
+// Splits text by the Unicode word boundaries (UAX #29), suitable for Latin like scripts
+pub struct WordTokenizer;
+
+impl Tokenizer for WordTokenizer {
+    fn tokenize(&self, text: &str) -> Vec<Token> {
+        text.split_word_bound_indices()
+            .filter(|(_, word)| word.chars().any(|c| c.is_alphanumeric()))
+            .map(|(start, word)| Token {
+                text: normalize_token(word),
+                start,
+                end: start + word.len(),
+            })
+            .collect()
+    }
+
+    fn name(&self) -> String {
+        "word".to_string()
+    }
+}
+
+// Segments runs of Chinese, Japanese and Korean characters into words found in the
+// dictionary (forward maximum matching), or into overlapping bigrams when there is no
+// dictionary. Other scripts are handled by `WordTokenizer`.
+pub struct CjkTokenizer {
+    dictionary: HashSet<String>,
+    max_word_chars: usize,
+}
+
+impl Default for CjkTokenizer {
+    fn default() -> Self {
+        CjkTokenizer::new()
+    }
+}
+
+impl CjkTokenizer {
+    pub fn new() -> CjkTokenizer {
+        CjkTokenizer {
+            dictionary: HashSet::new(),
+            max_word_chars: 0,
+        }
+    }
+
+    pub fn with_dictionary(words: &[String]) -> CjkTokenizer {
+        let mut tokenizer = CjkTokenizer::new();
+        for word in words {
+            tokenizer.add_word(word);
+        }
+        tokenizer
+    }
+
+    // Load a dictionary file with one word per line
+    pub fn load_dictionary(file_path: &str) -> Result<CjkTokenizer, Box<dyn Error>> {
+        let content = fs_file::read_to_string(file_path)?;
+        let words: Vec<String> = content
+            .lines()
+            .map(|l| l.trim().to_string())
+            .filter(|l| !l.is_empty())
+            .collect();
+
+        Ok(CjkTokenizer::with_dictionary(&words))
+    }
+
+    pub fn add_word(&mut self, word: &str) {
+        let word = normalize_token(word.trim());
+        let chars = word.chars().count();
+        if chars == 0 {
+            return;
+        }
+
+        self.max_word_chars = self.max_word_chars.max(chars);
+        self.dictionary.insert(word);
+    }
+
+    fn segment_run(&self, run: &str, offset: usize, tokens: &mut Vec<Token>) {
+        let chars: Vec<(usize, char)> = run.char_indices().collect();
+        let byte_end = |i: usize| -> usize {
+            match chars.get(i) {
+                Some((b, _)) => *b,
+                None => run.len(),
+            }
+        };
+        let mut push = |from: usize, to: usize| {
+            let start = byte_end(from);
+            let end = byte_end(to);
+            tokens.push(Token {
+                text: normalize_token(&run[start..end]),
+                start: offset + start,
+                end: offset + end,
+            });
+        };
+
+        if chars.len() == 1 {
+            push(0, 1);
+            return;
+        }
+
+        if self.dictionary.is_empty() {
+            for i in 0..chars.len() - 1 {
+                push(i, i + 2);
+            }
+            return;
+        }
+
+        let mut i = 0;
+        while i < chars.len() {
+            let mut len = self.max_word_chars.min(chars.len() - i);
+            while len > 1 {
+                let word = normalize_token(&run[byte_end(i)..byte_end(i + len)]);
+                if self.dictionary.contains(&word) {
+                    break;
+                }
+                len -= 1;
+            }
+            push(i, i + len.max(1));
+            i += len.max(1);
+        }
+    }
+}
+
+impl Tokenizer for CjkTokenizer {
+    fn tokenize(&self, text: &str) -> Vec<Token> {
+        let mut tokens: Vec<Token> = Vec::new();
+        let mut run_start = 0;
+        let mut run_is_cjk = false;
+
+        let flush = |from: usize, to: usize, is_cjk: bool, tokens: &mut Vec<Token>| {
+            if from >= to {
+                return;
+            }
+            if is_cjk {
+                self.segment_run(&text[from..to], from, tokens);
+            } else {
+                for mut t in WordTokenizer.tokenize(&text[from..to]) {
+                    t.start += from;
+                    t.end += from;
+                    tokens.push(t);
+                }
+            }
+        };
+
+        for (i, c) in text.char_indices() {
+            let c_is_cjk = is_cjk(c);
+            if c_is_cjk != run_is_cjk {
+                flush(run_start, i, run_is_cjk, &mut tokens);
+                run_start = i;
+                run_is_cjk = c_is_cjk;
+            }
+        }
+        flush(run_start, text.len(), run_is_cjk, &mut tokens);
+
+        tokens
+    }
+
+    fn is_ngram(&self) -> bool {
+        self.dictionary.is_empty()
+    }
+
+    fn name(&self) -> String {
+        if self.dictionary.is_empty() {
+            return "cjk".to_string();
+        }
+        let mut words: Vec<&str> = self.dictionary.iter().map(|w| w.as_str()).collect();
+        words.sort();
+        format!("cjk:{}", sha256_by_bytes(words.join("\n").as_bytes()))
+    }
+}
+
+pub fn is_cjk(c: char) -> bool {
+    matches!(c as u32,
+        0x3040..=0x30FF // Hiragana, Katakana
+        | 0x31F0..=0x31FF // Katakana phonetic extensions
+        | 0x3400..=0x4DBF // CJK unified ideographs extension A
+        | 0x4E00..=0x9FFF // CJK unified ideographs
+        | 0xAC00..=0xD7AF // Hangul syllables
+        | 0xF900..=0xFAFF // CJK compatibility ideographs
+        | 0xFF66..=0xFF9F // Half width Katakana
+        | 0x20000..=0x2FA1F // CJK unified ideographs extension B to F, compatibility supplement
+    )
+}
+
+fn normalize_token(s: &str) -> String {
+    s.chars().flat_map(|c| c.to_lowercase()).collect()
+}
+
+// Byte ranges of `string` where the tokens of `search` appear as consecutive whole words
+pub fn find_word_spans(
+    string: &str,
+    search: &str,
+    tokenizer: &dyn Tokenizer,
+) -> Vec<(usize, usize)> {
+    let query: Vec<String> = tokenizer
+        .tokenize(search)
+        .into_iter()
+        .map(|t| t.text)
+        .collect();
+    if query.is_empty() {
+        return [].to_vec();
+    }
+
+    let tokens = tokenizer.tokenize(string);
+    if tokenizer.is_ngram() && query.len() == 1 {
+        let mut chars = query[0].chars();
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            if is_cjk(c) {
+                return find_char_spans(string, &tokens, c);
+            }
+        }
+    }
+
+    let mut spans: Vec<(usize, usize)> = Vec::new();
+    let mut i = 0;
+    while i + query.len() <= tokens.len() {
+        let window = &tokens[i..i + query.len()];
+        if window.iter().zip(query.iter()).all(|(t, q)| &t.text == q) {
+            spans.push((window[0].start, window[window.len() - 1].end));
+            i += query.len();
+        } else {
+            i += 1;
+        }
+    }
+
+    spans
+}
+
+// Byte ranges of the CJK character `c` inside the n-gram tokens of `string`
+fn find_char_spans(string: &str, tokens: &[Token], c: char) -> Vec<(usize, usize)> {
+    let mut spans: Vec<(usize, usize)> = Vec::new();
+    for t in tokens {
+        for (i, tc) in string[t.start..t.end].char_indices() {
+            if tc == c {
+                spans.push((t.start + i, t.start + i + c.len_utf8()));
+            }
+        }
+    }
+    // Each character is in two overlapping bigrams
+    spans.sort();
+    spans.dedup();
+    spans
+}
+
+// Whole word version of `find_matches`, the output has the same format
+pub fn find_matches_whole_word(
+    string: &str,
+    search: &str,
+    tokenizer: &dyn Tokenizer,
+    context_size: usize,
+    prefix: &str,
+    postfix: &str,
+) -> Vec<String> {
+    find_word_spans(string, search, tokenizer)
+        .into_iter()
+        .map(|(start, end)| {
+            wrap_text(
+                string,
+                &string[start..end],
+                start,
+                end,
+                context_size,
+                prefix,
+                postfix,
+            )
+            .0
+        })
+        .collect()
+}
+
+#[test]
+fn test_cjk_tokenizer() {
+    let texts =
+        |tokens: Vec<Token>| -> Vec<String> { tokens.into_iter().map(|t| t.text).collect() };
+
+    let tokenizer = CjkTokenizer::new();
+    let string = "Rust 语言很快, can't stop";
+    let tokens = tokenizer.tokenize(string);
+    assert_eq!(
+        texts(tokens.clone()),
+        ["rust", "语言", "言很", "很快", "can't", "stop"]
+    );
+    assert_eq!(&string[tokens[1].start..tokens[1].end], "语言");
+
+    let tokenizer = CjkTokenizer::with_dictionary(&["开源".to_string(), "交流社区".to_string()]);
+    assert_eq!(
+        texts(tokenizer.tokenize("中文开源技术交流社区")),
+        ["中", "文", "开源", "技", "术", "交流社区"]
+    );
+    assert_eq!(texts(tokenizer.tokenize("ひらがなカタカナ")).len(), 8);
+}
+
+#[test]
+fn test_find_matches_whole_word() {
+    let string = "Rustacean loves rust, RUST async and 中文开源技术";
+
+    let res = find_matches_whole_word(string, "rust", &WordTokenizer, 5, "<b>", "</b>");
+    assert_eq!(res, ["oves <b>rust</b>, RUS", "ust, <b>RUST</b> asyn"]);
+
+    let spans = find_word_spans(string, "rust async", &CjkTokenizer::new());
+    assert_eq!(spans.len(), 1);
+    assert_eq!(&string[spans[0].0..spans[0].1], "RUST async");
+
+    let spans = find_word_spans(string, "开源技", &CjkTokenizer::new());
+    assert_eq!(&string[spans[0].0..spans[0].1], "开源技");
+
+    let spans = find_word_spans("开源，源码 源", "源", &CjkTokenizer::new());
+    assert_eq!(spans, [(3, 6), (9, 12), (16, 19)]);
+    let dictionary = CjkTokenizer::with_dictionary(&["开源".to_string()]);
+    assert!(find_word_spans(string, "源", &dictionary).is_empty());
+}
+
+#[test]
+fn test_search_word_mode() {
+    use crate::fs::path_buf_to_string;
+    use crate::search::{search_in_dir_with_options, SearchMode, SearchOptions};
+    use std::sync::Arc;
+
+    let dir = std::env::temp_dir().join("fivim_rs_utils_test_search_tokenizer");
+    let _ = std::fs::remove_dir_all(&dir);
+    let write = |name: &str, content: &str| {
+        let _ = fs_file::write_str(&path_buf_to_string(dir.join(name)), content);
+    };
+    write("a.md", "Rust is fast");
+    write("b.md", "a rustacean");
+    write("c.md", "开源，源码");
+
+    let mut options = SearchOptions::new();
+    options.mode = SearchMode::Word;
+    let paths = |search: &str, options: &SearchOptions| -> Vec<String> {
+        let mut res: Vec<String> = search_in_dir_with_options(&dir, search, options)
+            .unwrap()
+            .into_iter()
+            .map(|r| {
+                std::path::Path::new(&r.path)
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect();
+        res.sort();
+        res
+    };
+    assert_eq!(paths("rust", &options), ["a.md"]);
+    assert_eq!(paths("源", &options), ["c.md"]);
+
+    options.tokenizer = Some(Arc::new(CjkTokenizer::with_dictionary(&[
+        "开源".to_string()
+    ])));
+    assert_eq!(paths("开", &options), [] as [&str; 0]);
+    assert_eq!(paths("开源", &options), ["c.md"]);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -1,20 +1,32 @@
 use anyhow::Result;
 use base64::engine::general_purpose::STANDARD as b64_STANDARD;
 use base64::Engine;
-use reqwest::{self, header, Client, RequestBuilder};
+use reqwest::{self, Client, RequestBuilder};
 use serde::{Deserialize, Serialize};
-use std::fs;
 use std::path::Path;
 use std::{
     collections::HashMap,
     fs::File,
-    io::{Read, Write},
+    io::{self, ErrorKind, Read, Write},
 };
 
 use crate::fs as x_fs;
-use crate::progress as xu_progress;
-
-#[derive(PartialEq, Eq, Debug)]
+use crate::web_client::send_with_retry;
+
+pub use crate::web_client::HttpClient;
+pub use crate::web_client::HttpClientOptions;
+pub use crate::web_client::RetryPolicy;
+pub use crate::web_download::download_resumable;
+pub use crate::web_download::download_segmented;
+pub use crate::web_download::DownloadOptions;
+pub use crate::web_download::DownloadRes;
+pub use crate::web_upload::upload;
+pub use crate::web_upload::upload_with_client;
+pub use crate::web_upload::FormPart;
+pub use crate::web_upload::FormValue;
+pub use crate::web_upload::RequestBody;
+
+#[derive(PartialEq, Eq, Debug, Clone)]
 pub enum HttpMethod {
     None,
     Get,
@@ -24,6 +36,16 @@ pub enum HttpMethod {
     Head,
 }
 
+impl HttpMethod {
+    // Whether sending the request twice has the same effect as sending it once
+    pub fn is_idempotent(&self) -> bool {
+        matches!(
+            self,
+            HttpMethod::Get | HttpMethod::Put | HttpMethod::Delete | HttpMethod::Head
+        )
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum ReaponseDataType {
     None,
@@ -51,13 +73,13 @@ impl HttpResponse {
     }
 }
 
-fn build_client(
+pub(crate) fn build_request(
+    client: &Client,
     url: &str,
-    method: HttpMethod,
+    method: &HttpMethod,
     headers_map: HashMap<String, String>,
     params_map: HashMap<String, String>,
-) -> (Client, RequestBuilder) {
-    let client = reqwest::Client::new();
+) -> Result<RequestBuilder, io::Error> {
     let mut req: reqwest::RequestBuilder;
 
     match method {
@@ -76,7 +98,12 @@ fn build_client(
         HttpMethod::Head => {
             req = client.head(url);
         }
-        HttpMethod::None => todo!(),
+        HttpMethod::None => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "the http method is None",
+            ))
+        }
     }
 
     for (k, v) in headers_map {
@@ -88,7 +115,7 @@ fn build_client(
         params.insert(k, v);
     }
 
-    return (client, req.query(&params));
+    Ok(req.query(&params))
 }
 
 pub async fn request_data(
@@ -99,15 +126,47 @@ pub async fn request_data(
     body: String,
     resp_data_type: ReaponseDataType,
 ) -> Result<HttpResponse, Box<dyn std::error::Error>> {
-    let bs = build_client(url, method, headers_map.clone(), params_map.clone());
-    let mut request = bs.1;
+    request_data_with_client(
+        &HttpClient::shared()?,
+        method,
+        url,
+        headers_map,
+        params_map,
+        body,
+        resp_data_type,
+    )
+    .await
+}
+
+pub async fn request_data_with_client(
+    client: &HttpClient,
+    method: HttpMethod,
+    url: &str,
+    headers_map: &HashMap<String, String>,
+    params_map: &HashMap<String, String>,
+    body: String,
+    resp_data_type: ReaponseDataType,
+) -> Result<HttpResponse, Box<dyn std::error::Error>> {
+    let mut request = build_request(
+        client.inner(),
+        url,
+        &method,
+        headers_map.clone(),
+        params_map.clone(),
+    )?;
 
     if body.len() > 0 {
         request = request.body(body);
     }
 
-    let ret = request.send().await?;
+    let ret = send_with_retry(client, &method, request).await?;
+    read_response(ret, resp_data_type).await
+}
 
+pub(crate) async fn read_response(
+    ret: reqwest::Response,
+    resp_data_type: ReaponseDataType,
+) -> Result<HttpResponse, Box<dyn std::error::Error>> {
     let mut res = HttpResponse::new();
     res.status = ret.status().as_u16();
     res.headers = HashMap::new();
@@ -139,11 +198,36 @@ pub async fn downlaod_file(
     file_path: &str,
     headers_map: &HashMap<String, String>,
     params_map: &HashMap<String, String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    downlaod_file_with_client(
+        &HttpClient::shared()?,
+        method,
+        url,
+        file_path,
+        headers_map,
+        params_map,
+    )
+    .await
+}
+
+pub async fn downlaod_file_with_client(
+    client: &HttpClient,
+    method: HttpMethod,
+    url: &str,
+    file_path: &str,
+    headers_map: &HashMap<String, String>,
+    params_map: &HashMap<String, String>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     crate::fs::check_or_create_dir(&x_fs::get_parent_dir_path(&file_path))?;
 
-    let (_client, req) = build_client(url, method, headers_map.clone(), params_map.clone());
-    let ret = req.send().await?;
+    let req = build_request(
+        client.inner(),
+        url,
+        &method,
+        headers_map.clone(),
+        params_map.clone(),
+    )?;
+    let ret = send_with_retry(client, &method, req).await?;
 
     let body = ret.bytes().await?;
     let mut file = File::create(Path::new(file_path))?;
@@ -162,50 +246,41 @@ pub async fn downlaod_file_large(
     params_map: &HashMap<String, String>,
     progress_name: &str,
 ) -> Result<i32, Box<dyn std::error::Error>> {
-    let _ = crate::fs::check_or_create_dir(&x_fs::get_parent_dir_path(&file_path))?;
-
-    let path = Path::new(&file_path);
-    xu_progress::insert_new(&progress_name);
-
-    let bs = build_client(url, method, headers_map.clone(), params_map.clone());
-    let client = bs.0;
-    let mut request = bs.1;
-
-    let total_size = {
-        let resp = client.head(url).send().await?;
-        if resp.status().is_success() {
-            resp.headers()
-                .get(header::CONTENT_LENGTH)
-                .and_then(|ct_len| ct_len.to_str().ok())
-                .and_then(|ct_len| ct_len.parse().ok())
-                .unwrap_or(0)
-        } else {
-            // println!(">>> not is_success status : {} ", resp.status());
-            0
-        }
-    };
+    downlaod_file_large_with_client(
+        &HttpClient::shared()?,
+        method,
+        url,
+        file_path,
+        headers_map,
+        params_map,
+        progress_name,
+    )
+    .await
+}
 
-    if path.exists() {
-        let size = path.metadata()?.len().saturating_sub(1);
-        request = request.header(header::RANGE, format!("bytes={}-", size));
-    }
-    let mut source = request.send().await?;
-    let mut dest = fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(&path)?;
-    let mut downloaded_size: i32 = 0;
-    while let Some(chunk) = source.chunk().await? {
-        dest.write_all(&chunk)?;
-
-        if total_size > 0 {
-            let pct = downloaded_size as f32 / total_size as f32;
-            xu_progress::set(&progress_name, pct, "");
-            downloaded_size += chunk.len() as i32;
-        }
-    }
-    xu_progress::set(&progress_name, 1.0, "");
-    Ok(total_size)
+pub async fn downlaod_file_large_with_client(
+    client: &HttpClient,
+    method: HttpMethod,
+    url: &str,
+    file_path: &str,
+    headers_map: &HashMap<String, String>,
+    params_map: &HashMap<String, String>,
+    progress_name: &str,
+) -> Result<i32, Box<dyn std::error::Error>> {
+    let mut options = DownloadOptions::new();
+    options.progress_name = progress_name.to_string();
+    let res = download_resumable(
+        client,
+        method,
+        url,
+        file_path,
+        headers_map,
+        params_map,
+        &options,
+    )
+    .await?;
+    // Saturates for files over 2 GiB, use `download_resumable` for the exact size
+    Ok(res.total_size.min(i32::MAX as u64) as i32)
 }
 
 #[cfg(test)]
@@ -213,6 +288,25 @@ pub async fn downlaod_file_large(
 mod tests {
     use super::*;
 
+    #[tokio::test]
+    async fn test_method_none() {
+        assert!(!HttpMethod::None.is_idempotent());
+        assert!(!HttpMethod::Post.is_idempotent());
+        assert!(HttpMethod::Put.is_idempotent());
+
+        let empty: HashMap<String, String> = HashMap::new();
+        let res = request_data(
+            HttpMethod::None,
+            "http://127.0.0.1:1/",
+            &empty,
+            &empty,
+            "".to_string(),
+            ReaponseDataType::Text,
+        )
+        .await;
+        assert!(res.is_err());
+    }
+
     #[tokio::test]
     async fn test_request_text() {
         let mut header: HashMap<String, String> = HashMap::new();
@@ -0,0 +1,472 @@
+use chrono::{DateTime, Utc};
+use log::debug;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER};
+use reqwest::{redirect, Certificate, Client, Proxy, RequestBuilder, Response};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+use crate::web::HttpMethod;
+
+lazy_static! {
+    // The build error is kept as text, the functions using the shared client return it
+    static ref SHARED_CLIENT: Result<HttpClient, String> =
+        HttpClient::new(&HttpClientOptions::unpooled()).map_err(|e| e.to_string());
+}
+
+// Options of an `HttpClient`, durations in milliseconds with 0 for no limit
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct HttpClientOptions {
+    // Whole request, including reading the body
+    pub timeout_ms: u64,
+    pub connect_timeout_ms: u64,
+    pub user_agent: String,
+    // Proxy for all the requests like `http://127.0.0.1:8080` or `socks5://...`, none if empty
+    pub proxy: String,
+    // Sent with every request, the headers of a request override them
+    pub default_headers: HashMap<String, String>,
+    // Redirects followed before failing, 0 to not follow redirects
+    pub max_redirects: usize,
+    // Idle connections kept per host, and how long
+    pub pool_max_idle_per_host: usize,
+    pub pool_idle_timeout_ms: u64,
+    // PEM files of extra root certificates, for self-hosted servers
+    pub root_cert_paths: Vec<String>,
+    // Only for testing against servers with self-signed certificates
+    pub accept_invalid_certs: bool,
+    pub retry: RetryPolicy,
+}
+
+impl Default for HttpClientOptions {
+    fn default() -> Self {
+        HttpClientOptions::new()
+    }
+}
+
+impl HttpClientOptions {
+    pub fn new() -> HttpClientOptions {
+        HttpClientOptions {
+            timeout_ms: 0,
+            connect_timeout_ms: 30_000,
+            user_agent: format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            proxy: "".to_string(),
+            default_headers: HashMap::new(),
+            max_redirects: 10,
+            pool_max_idle_per_host: 32,
+            pool_idle_timeout_ms: 90_000,
+            root_cert_paths: [].to_vec(),
+            accept_invalid_certs: false,
+            retry: RetryPolicy::new(),
+        }
+    }
+
+    // The default options without idle connections kept, for a client used from several
+    // tokio runtimes
+    pub fn unpooled() -> HttpClientOptions {
+        let mut options = HttpClientOptions::new();
+        options.pool_max_idle_per_host = 0;
+        options
+    }
+}
+
+// When and how long to wait before sending a request again, durations in milliseconds
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct RetryPolicy {
+    // Attempts including the first one, 1 to never retry
+    pub max_attempts: u32,
+    // The delay before the nth retry is `initial_backoff_ms * multiplier^(n-1)`, at most
+    // `max_backoff_ms`
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub multiplier: f64,
+    // Part of the delay that is random, from 0 for none to 1 for a delay between 0 and the
+    // backoff
+    pub jitter: f64,
+    // Responses with these statuses are retried, connection errors and timeouts always are
+    pub retry_statuses: Vec<u16>,
+    // Wait as long as the `Retry-After` header of the response asks, the response is returned
+    // without retrying if it asks more than `max_retry_after_ms`
+    pub respect_retry_after: bool,
+    pub max_retry_after_ms: u64,
+    // Also retry POST requests, which may not be idempotent
+    pub retry_non_idempotent: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::new()
+    }
+}
+
+impl RetryPolicy {
+    pub fn new() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 30_000,
+            multiplier: 2.0,
+            jitter: 0.5,
+            retry_statuses: [408, 429, 500, 502, 503, 504].to_vec(),
+            respect_retry_after: true,
+            max_retry_after_ms: 120_000,
+            retry_non_idempotent: false,
+        }
+    }
+
+    // A policy that never retries
+    pub fn none() -> RetryPolicy {
+        let mut policy = RetryPolicy::new();
+        policy.max_attempts = 1;
+        policy
+    }
+
+    pub fn allows(&self, method: &HttpMethod) -> bool {
+        self.max_attempts > 1 && (self.retry_non_idempotent || method.is_idempotent())
+    }
+
+    // The delay before the retry following the `attempt`th attempt (from 1)
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self
+            .multiplier
+            .max(1.0)
+            .powi(attempt.saturating_sub(1) as i32);
+        let backoff = (self.initial_backoff_ms as f64 * exp).min(self.max_backoff_ms as f64);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let random = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+
+        Duration::from_millis((backoff * (1.0 - jitter * random)) as u64)
+    }
+}
+
+// The delay asked by a `Retry-After` header, in seconds or as an HTTP date
+pub fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+// Send a request, and send it again according to the retry policy of the client. Requests with
+// a streamed body can not be sent twice and are sent once.
+pub(crate) async fn send_with_retry(
+    client: &HttpClient,
+    method: &HttpMethod,
+    request: RequestBuilder,
+) -> Result<Response, reqwest::Error> {
+    let policy = &client.options.retry;
+    let retryable = policy.allows(method);
+
+    let mut attempt = 1;
+    loop {
+        let current = if retryable && attempt < policy.max_attempts {
+            request.try_clone()
+        } else {
+            None
+        };
+        let current = match current {
+            Some(r) => r,
+            None => return request.send().await,
+        };
+
+        let mut delay = policy.backoff(attempt);
+        match current.send().await {
+            Ok(resp) if policy.retry_statuses.contains(&resp.status().as_u16()) => {
+                let retry_after = resp
+                    .headers()
+                    .get(RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| parse_retry_after(v, Utc::now()));
+                if let (true, Some(after)) = (policy.respect_retry_after, retry_after) {
+                    if after > Duration::from_millis(policy.max_retry_after_ms) {
+                        return Ok(resp);
+                    }
+                    delay = delay.max(after);
+                }
+                debug!("retry {} after status {}", attempt, resp.status());
+            }
+            Ok(resp) => return Ok(resp),
+            Err(e) if e.is_connect() || e.is_timeout() => {
+                debug!("retry {} after error {}", attempt, e);
+            }
+            Err(e) => return Err(e),
+        }
+
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+// A configured `reqwest::Client`. Cloning is cheap and the clones share the connection pool,
+// so one client should be built and reused for all the requests.
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+    client: Client,
+    options: HttpClientOptions,
+}
+
+impl HttpClient {
+    pub fn new(options: &HttpClientOptions) -> Result<HttpClient, Box<dyn Error>> {
+        let mut headers = HeaderMap::new();
+        for (k, v) in &options.default_headers {
+            headers.insert(
+                HeaderName::try_from(k.as_str())?,
+                HeaderValue::try_from(v.as_str())?,
+            );
+        }
+
+        let mut builder = Client::builder()
+            .user_agent(&options.user_agent)
+            .default_headers(headers)
+            .pool_max_idle_per_host(options.pool_max_idle_per_host)
+            .danger_accept_invalid_certs(options.accept_invalid_certs);
+        if options.timeout_ms > 0 {
+            builder = builder.timeout(Duration::from_millis(options.timeout_ms));
+        }
+        if options.connect_timeout_ms > 0 {
+            builder = builder.connect_timeout(Duration::from_millis(options.connect_timeout_ms));
+        }
+        if options.pool_idle_timeout_ms > 0 {
+            builder =
+                builder.pool_idle_timeout(Duration::from_millis(options.pool_idle_timeout_ms));
+        }
+        builder = builder.redirect(if options.max_redirects > 0 {
+            redirect::Policy::limited(options.max_redirects)
+        } else {
+            redirect::Policy::none()
+        });
+        if !options.proxy.is_empty() {
+            builder = builder.proxy(Proxy::all(&options.proxy)?);
+        }
+        for path in &options.root_cert_paths {
+            builder = builder.add_root_certificate(Certificate::from_pem(&fs::read(path)?)?);
+        }
+
+        Ok(HttpClient {
+            client: builder.build()?,
+            options: options.clone(),
+        })
+    }
+
+    // The client used by the functions of `web` without a client. Pooled connections belong to
+    // the tokio runtime that opened them and callers may create a runtime per call, so it
+    // keeps no idle connection; pass a client to the `*_with_client` functions to reuse
+    // connections. Fails if the TLS backend could not be initialized.
+    pub fn shared() -> Result<HttpClient, Box<dyn Error>> {
+        match &*SHARED_CLIENT {
+            Ok(client) => Ok(client.clone()),
+            Err(e) => Err(format!("build the default http client: {}", e).into()),
+        }
+    }
+
+    pub fn options(&self) -> &HttpClientOptions {
+        &self.options
+    }
+
+    pub fn inner(&self) -> &Client {
+        &self.client
+    }
+}
+
+// A server answering the given raw responses in order, one per connection. Returns its url
+// and the raw requests it received.
+#[cfg(test)]
+pub(crate) fn serve_http(
+    responses: Vec<Vec<u8>>,
+) -> (String, std::thread::JoinHandle<Vec<Vec<u8>>>) {
+    let mut responses = responses.into_iter();
+    serve_http_with(responses.len(), move |_| responses.next().unwrap())
+}
+
+// Serve `count` connections one after the other, answering each request with `respond`
+#[cfg(test)]
+pub(crate) fn serve_http_with(
+    count: usize,
+    mut respond: impl FnMut(&[u8]) -> Vec<u8> + Send + 'static,
+) -> (String, std::thread::JoinHandle<Vec<Vec<u8>>>) {
+    use std::io::{Read, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let handle = std::thread::spawn(move || {
+        let mut requests: Vec<Vec<u8>> = Vec::new();
+        for _ in 0..count {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut req: Vec<u8> = Vec::new();
+            let mut buf = [0; 8192];
+            // The head, then the body announced by Content-Length or chunked until its end
+            loop {
+                let n = stream.read(&mut buf).unwrap();
+                req.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&req).to_lowercase();
+                let head_end = match text.find("\r\n\r\n") {
+                    Some(i) => i + 4,
+                    None => continue,
+                };
+                let len = text
+                    .lines()
+                    .find_map(|l| l.strip_prefix("content-length:"))
+                    .and_then(|v| v.trim().parse::<usize>().ok());
+                let done = match len {
+                    Some(len) => req.len() >= head_end + len,
+                    None if text.contains("transfer-encoding: chunked") => {
+                        text.ends_with("0\r\n\r\n")
+                    }
+                    None => true,
+                };
+                if done || n == 0 {
+                    break;
+                }
+            }
+            let _ = stream.write_all(&respond(&req));
+            requests.push(req);
+        }
+        requests
+    });
+
+    (url, handle)
+}
+
+#[cfg(test)]
+pub(crate) fn http_response(status: &str, headers: &[&str], body: &[u8]) -> Vec<u8> {
+    let mut res = format!("HTTP/1.1 {}\r\nConnection: close\r\n", status);
+    for h in headers {
+        res.push_str(h);
+        res.push_str("\r\n");
+    }
+    res.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+    let mut res = res.into_bytes();
+    res.extend_from_slice(body);
+    res
+}
+
+#[test]
+fn test_http_client() {
+    let mut options = HttpClientOptions::new();
+    options.timeout_ms = 5000;
+    options.proxy = "http://127.0.0.1:8080".to_string();
+    options
+        .default_headers
+        .insert("PRIVATE-TOKEN".to_string(), "token".to_string());
+    let client = HttpClient::new(&options).unwrap();
+    assert_eq!(client.options().timeout_ms, 5000);
+
+    options
+        .default_headers
+        .insert("bad header".to_string(), "x".to_string());
+    assert!(HttpClient::new(&options).is_err());
+
+    let json = serde_json::to_string(&HttpClientOptions::new()).unwrap();
+    let parsed: HttpClientOptions = serde_json::from_str(r#"{"timeout_ms": 10}"#).unwrap();
+    assert!(json.contains("fivim-rs-utils"));
+    assert_eq!(parsed.timeout_ms, 10);
+    assert_eq!(parsed.max_redirects, 10);
+    assert_eq!(parsed.pool_max_idle_per_host, 32);
+    let shared = HttpClient::shared().unwrap();
+    assert_eq!(shared.options().pool_max_idle_per_host, 0);
+}
+
+#[test]
+fn test_retry_policy() {
+    let mut policy = RetryPolicy::new();
+    policy.jitter = 0.0;
+    assert_eq!(policy.backoff(1), Duration::from_millis(500));
+    assert_eq!(policy.backoff(3), Duration::from_millis(2000));
+    assert_eq!(policy.backoff(20), Duration::from_millis(30_000));
+    policy.jitter = 1.0;
+    assert!(policy.backoff(2) <= Duration::from_millis(1000));
+
+    assert!(policy.allows(&HttpMethod::Get));
+    assert!(!policy.allows(&HttpMethod::Post));
+    assert!(!RetryPolicy::none().allows(&HttpMethod::Get));
+
+    let now = DateTime::parse_from_rfc2822("Wed, 21 Oct 2015 07:28:00 GMT")
+        .unwrap()
+        .with_timezone(&Utc);
+    assert_eq!(
+        parse_retry_after(" 120", now),
+        Some(Duration::from_secs(120))
+    );
+    assert_eq!(
+        parse_retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now),
+        Some(Duration::from_secs(30))
+    );
+    assert_eq!(parse_retry_after("soon", now), None);
+}
+
+#[tokio::test]
+async fn test_send_with_retry() {
+    use crate::web::{request_data_with_client, ReaponseDataType};
+
+    let mut options = HttpClientOptions::new();
+    options.retry.initial_backoff_ms = 1;
+    let client = HttpClient::new(&options).unwrap();
+    let headers: HashMap<String, String> = HashMap::new();
+
+    let (url, server) = serve_http(
+        [
+            http_response("503 Service Unavailable", &["Retry-After: 0"], b""),
+            http_response("429 Too Many Requests", &[], b""),
+            http_response("200 OK", &[], b"ok"),
+        ]
+        .to_vec(),
+    );
+    let res = request_data_with_client(
+        &client,
+        HttpMethod::Get,
+        &url,
+        &headers,
+        &headers,
+        "".to_string(),
+        ReaponseDataType::Text,
+    )
+    .await
+    .unwrap();
+    assert_eq!((res.status, res.text.as_str()), (200, "ok"));
+    assert_eq!(server.join().unwrap().len(), 3);
+
+    let (url, server) = serve_http(
+        [
+            http_response("503 Service Unavailable", &[], b""),
+            http_response("503 Service Unavailable", &["Retry-After: 3600"], b""),
+        ]
+        .to_vec(),
+    );
+    let post = request_data_with_client(
+        &client,
+        HttpMethod::Post,
+        &url,
+        &headers,
+        &headers,
+        "body".to_string(),
+        ReaponseDataType::Text,
+    )
+    .await
+    .unwrap();
+    assert_eq!(post.status, 503);
+    let get = request_data_with_client(
+        &client,
+        HttpMethod::Get,
+        &url,
+        &headers,
+        &headers,
+        "".to_string(),
+        ReaponseDataType::Text,
+    )
+    .await
+    .unwrap();
+    assert_eq!(get.status, 503);
+    assert_eq!(server.join().unwrap().len(), 2);
+}
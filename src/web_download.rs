@@ -0,0 +1,735 @@
+use reqwest::header::{self, HeaderMap};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::task::JoinSet;
+
+use crate::fs as x_fs;
+use crate::hash::sha256_by_file_path;
+use crate::progress as xu_progress;
+use crate::web::{build_request, HttpMethod};
+use crate::web_client::{send_with_retry, HttpClient};
+
+const PART_EXT: &str = "part";
+const PART_META_EXT: &str = "part.json";
+
+const DEFAULT_SEGMENTS: usize = 4;
+const DEFAULT_MIN_SEGMENT_SIZE: u64 = 1024 * 1024;
+
+// Options of `download_resumable` and `download_segmented`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DownloadOptions {
+    // Name of the `progress` entry updated while downloading, none if empty
+    pub progress_name: String,
+    // Expected SHA-256 of the file in hex, not checked if empty
+    pub sha256: String,
+    // Start again from zero instead of resuming an interrupted download
+    pub no_resume: bool,
+    // Byte ranges fetched concurrently by `download_segmented`
+    pub segments: usize,
+    // Smaller files are split in fewer segments
+    pub min_segment_size: u64,
+}
+
+impl DownloadOptions {
+    pub fn new() -> DownloadOptions {
+        DownloadOptions {
+            progress_name: "".to_string(),
+            sha256: "".to_string(),
+            no_resume: false,
+            segments: DEFAULT_SEGMENTS,
+            min_segment_size: DEFAULT_MIN_SEGMENT_SIZE,
+        }
+    }
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        DownloadOptions::new()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DownloadRes {
+    pub total_size: u64,
+    // Bytes already downloaded by an interrupted download, 0 if it started from zero
+    pub resumed_from: u64,
+}
+
+// What identifies the remote file of an interrupted download, saved next to the `.part` file
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct PartMeta {
+    url: String,
+    etag: String,
+    last_modified: String,
+}
+
+impl PartMeta {
+    // The `If-Range` validator, a strong ETag or else the modification date
+    fn validator(&self) -> Option<&str> {
+        if !self.etag.is_empty() && !self.etag.starts_with("W/") {
+            return Some(&self.etag);
+        }
+        if !self.last_modified.is_empty() {
+            return Some(&self.last_modified);
+        }
+        None
+    }
+}
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> String {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .to_string()
+}
+
+// Parse `bytes start-end/total`, the total is None if unknown (`*`)
+pub(crate) fn parse_content_range(value: &str) -> Option<(u64, u64, Option<u64>)> {
+    let (range, total) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let total = match total {
+        "*" => None,
+        t => Some(t.parse().ok()?),
+    };
+    let (start, end) = range.split_once('-')?;
+    Some((start.parse().ok()?, end.parse().ok()?, total))
+}
+
+fn download_err(msg: String) -> Box<dyn Error> {
+    Box::new(io::Error::new(ErrorKind::InvalidData, msg))
+}
+
+fn remove_part(part_path: &str, meta_path: &str) {
+    let _ = fs::remove_file(part_path);
+    let _ = fs::remove_file(meta_path);
+}
+
+// Download a file to `file_path.part` and rename it once complete, resuming from the `.part`
+// file left by an interrupted download. The server must answer the range with a 206 starting
+// where the part ends; if the remote file changed since (checked by `If-Range` with its ETag or
+// modification date) or ranges are not supported it answers 200 and the download restarts.
+pub async fn download_resumable(
+    client: &HttpClient,
+    method: HttpMethod,
+    url: &str,
+    file_path: &str,
+    headers_map: &HashMap<String, String>,
+    params_map: &HashMap<String, String>,
+    options: &DownloadOptions,
+) -> Result<DownloadRes, Box<dyn Error>> {
+    x_fs::check_or_create_dir(&x_fs::get_parent_dir_path(file_path))?;
+    let part_path = format!("{}.{}", file_path, PART_EXT);
+    let meta_path = format!("{}.{}", file_path, PART_META_EXT);
+    if !options.progress_name.is_empty() {
+        xu_progress::insert_new(&options.progress_name);
+    }
+
+    let mut part_len = match fs::metadata(&part_path) {
+        Ok(m) if !options.no_resume => m.len(),
+        _ => 0,
+    };
+    let meta: Option<PartMeta> = fs::read_to_string(&meta_path)
+        .ok()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .filter(|m: &PartMeta| m.url == url);
+
+    let mut request = build_request(
+        client.inner(),
+        url,
+        &method,
+        headers_map.clone(),
+        params_map.clone(),
+    )?;
+    // Without a validator the part may belong to another version of the file
+    match meta.as_ref().and_then(|m| m.validator()) {
+        Some(validator) if part_len > 0 => {
+            request = request
+                .header(header::RANGE, format!("bytes={}-", part_len))
+                .header(header::IF_RANGE, validator);
+        }
+        _ => part_len = 0,
+    }
+
+    let resp = send_with_retry(client, &method, request).await?;
+    save_download(resp, url, file_path, part_len, options).await
+}
+
+// Write the answer to a download request into `file_path.part`, appended to the first
+// `part_len` bytes if it is a 206, and rename it once complete
+async fn save_download(
+    mut resp: reqwest::Response,
+    url: &str,
+    file_path: &str,
+    mut part_len: u64,
+    options: &DownloadOptions,
+) -> Result<DownloadRes, Box<dyn Error>> {
+    let part_path = format!("{}.{}", file_path, PART_EXT);
+    let meta_path = format!("{}.{}", file_path, PART_META_EXT);
+    let total_size = match resp.status() {
+        StatusCode::PARTIAL_CONTENT => {
+            let range = parse_content_range(&header_str(resp.headers(), header::CONTENT_RANGE));
+            match range {
+                Some((start, _, total)) if start == part_len => {
+                    total.unwrap_or(part_len + resp.content_length().unwrap_or(0))
+                }
+                _ => {
+                    remove_part(&part_path, &meta_path);
+                    return Err(download_err(format!(
+                        "unexpected Content-Range for bytes={}-: {:?}",
+                        part_len, range
+                    )));
+                }
+            }
+        }
+        // The part is already complete
+        StatusCode::RANGE_NOT_SATISFIABLE if part_len > 0 => {
+            let range = parse_content_range(&header_str(resp.headers(), header::CONTENT_RANGE));
+            let total = header_str(resp.headers(), header::CONTENT_RANGE)
+                .strip_prefix("bytes */")
+                .and_then(|t| t.parse::<u64>().ok());
+            if total != Some(part_len) || range.is_some() {
+                remove_part(&part_path, &meta_path);
+                return Err(download_err(format!(
+                    "range not satisfiable for a part of {} bytes",
+                    part_len
+                )));
+            }
+            part_len
+        }
+        s if s.is_success() => {
+            part_len = 0;
+            let meta = PartMeta {
+                url: url.to_string(),
+                etag: header_str(resp.headers(), header::ETAG),
+                last_modified: header_str(resp.headers(), header::LAST_MODIFIED),
+            };
+            fs::write(&meta_path, serde_json::to_string(&meta)?)?;
+            resp.content_length().unwrap_or(0)
+        }
+        s => {
+            return Err(download_err(format!("download failed with status {}", s)));
+        }
+    };
+
+    let mut dest = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(part_len > 0)
+        .truncate(part_len == 0)
+        .open(&part_path)?;
+    let mut downloaded = part_len;
+    if resp.status() != StatusCode::RANGE_NOT_SATISFIABLE {
+        while let Some(chunk) = resp.chunk().await? {
+            dest.write_all(&chunk)?;
+            downloaded += chunk.len() as u64;
+            if total_size > 0 && !options.progress_name.is_empty() {
+                let pct = downloaded as f32 / total_size as f32;
+                xu_progress::set(&options.progress_name, pct.min(1.0), "");
+            }
+        }
+    }
+    dest.sync_all()?;
+    drop(dest);
+
+    if total_size > 0 && downloaded != total_size {
+        // Keep the part to resume later
+        return Err(download_err(format!(
+            "incomplete download: {} of {} bytes",
+            downloaded, total_size
+        )));
+    }
+    if !options.sha256.is_empty() {
+        let sha256 = sha256_by_file_path(&part_path)?;
+        if !sha256.eq_ignore_ascii_case(&options.sha256) {
+            remove_part(&part_path, &meta_path);
+            return Err(download_err(format!(
+                "sha256 mismatch: expected {}, got {}",
+                options.sha256, sha256
+            )));
+        }
+    }
+
+    fs::rename(&part_path, Path::new(file_path))?;
+    let _ = fs::remove_file(&meta_path);
+    if !options.progress_name.is_empty() {
+        xu_progress::set(&options.progress_name, 1.0, "");
+    }
+
+    Ok(DownloadRes {
+        total_size: downloaded,
+        resumed_from: part_len,
+    })
+}
+
+// A byte range of `download_segmented`, written in place into the part file
+struct Segment {
+    start: u64,
+    end: u64,
+    // `If-Range` validator of the probed file, a changed file fails instead of being mixed
+    validator: Option<String>,
+}
+
+async fn download_segment(
+    client: HttpClient,
+    request: reqwest::RequestBuilder,
+    method: HttpMethod,
+    part_path: String,
+    segment: Segment,
+    on_chunk: impl Fn(u64),
+) -> Result<(), io::Error> {
+    let to_io_err = |e: reqwest::Error| io::Error::other(e);
+    let mut request = request.header(
+        header::RANGE,
+        format!("bytes={}-{}", segment.start, segment.end),
+    );
+    if let Some(validator) = &segment.validator {
+        request = request.header(header::IF_RANGE, validator.as_str());
+    }
+
+    let mut resp = send_with_retry(&client, &method, request)
+        .await
+        .map_err(to_io_err)?;
+    let range = parse_content_range(&header_str(resp.headers(), header::CONTENT_RANGE));
+    if resp.status() != StatusCode::PARTIAL_CONTENT
+        || range.map(|r| (r.0, r.1)) != Some((segment.start, segment.end))
+    {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "unexpected answer to bytes={}-{}: {} {:?}",
+                segment.start,
+                segment.end,
+                resp.status(),
+                range
+            ),
+        ));
+    }
+
+    let mut dest = OpenOptions::new().write(true).open(&part_path)?;
+    dest.seek(SeekFrom::Start(segment.start))?;
+    let mut written = 0;
+    while let Some(chunk) = resp.chunk().await.map_err(to_io_err)? {
+        dest.write_all(&chunk)?;
+        written += chunk.len() as u64;
+        on_chunk(chunk.len() as u64);
+    }
+    if written != segment.end - segment.start + 1 {
+        return Err(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            format!(
+                "incomplete segment bytes={}-{}: {} bytes",
+                segment.start, segment.end, written
+            ),
+        ));
+    }
+    dest.sync_all()
+}
+
+// Download a file in `options.segments` byte ranges fetched concurrently and written in place
+// into `file_path.part`, renamed once all are complete. The server is probed with the first
+// byte: if it answers with the whole file it is saved from that answer, if it does not answer
+// with a 206 and the file size, or the file is smaller than two segments, this falls back to a
+// single stream with `download_resumable`. A failed segmented
+// download is not resumed, its part is removed. The progress is of all the segments together.
+pub async fn download_segmented(
+    client: &HttpClient,
+    method: HttpMethod,
+    url: &str,
+    file_path: &str,
+    headers_map: &HashMap<String, String>,
+    params_map: &HashMap<String, String>,
+    options: &DownloadOptions,
+) -> Result<DownloadRes, Box<dyn Error>> {
+    let request = || {
+        build_request(
+            client.inner(),
+            url,
+            &method,
+            headers_map.clone(),
+            params_map.clone(),
+        )
+    };
+
+    let probe = if options.segments > 1 {
+        let probe = request()?.header(header::RANGE, "bytes=0-0");
+        let resp = send_with_retry(client, &method, probe).await?;
+        let range = parse_content_range(&header_str(resp.headers(), header::CONTENT_RANGE));
+        match (resp.status(), range) {
+            (StatusCode::PARTIAL_CONTENT, Some((0, _, Some(total)))) => Some((total, resp)),
+            // Ranges are not supported, the file is already on its way
+            (StatusCode::OK, _) => {
+                x_fs::check_or_create_dir(&x_fs::get_parent_dir_path(file_path))?;
+                if !options.progress_name.is_empty() {
+                    xu_progress::insert_new(&options.progress_name);
+                }
+                return save_download(resp, url, file_path, 0, options).await;
+            }
+            _ => None,
+        }
+    } else {
+        None
+    };
+    let (total_size, resp) = match probe {
+        Some((total, resp)) if total >= options.min_segment_size.max(1) * 2 => (total, resp),
+        _ => {
+            return download_resumable(
+                client,
+                method,
+                url,
+                file_path,
+                headers_map,
+                params_map,
+                options,
+            )
+            .await
+        }
+    };
+    let meta = PartMeta {
+        url: url.to_string(),
+        etag: header_str(resp.headers(), header::ETAG),
+        last_modified: header_str(resp.headers(), header::LAST_MODIFIED),
+    };
+    drop(resp);
+
+    x_fs::check_or_create_dir(&x_fs::get_parent_dir_path(file_path))?;
+    let part_path = format!("{}.{}", file_path, PART_EXT);
+    let meta_path = format!("{}.{}", file_path, PART_META_EXT);
+    // The part is sparse until complete and can not be resumed by `download_resumable`
+    let _ = fs::remove_file(&meta_path);
+    OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&part_path)?
+        .set_len(total_size)?;
+    if !options.progress_name.is_empty() {
+        xu_progress::insert_new(&options.progress_name);
+    }
+
+    let count = (options.segments as u64).min(total_size / options.min_segment_size.max(1));
+    let downloaded = Arc::new(AtomicU64::new(0));
+    let mut tasks = JoinSet::new();
+    for i in 0..count {
+        let segment = Segment {
+            start: total_size * i / count,
+            end: total_size * (i + 1) / count - 1,
+            validator: meta.validator().map(|v| v.to_string()),
+        };
+        let downloaded = downloaded.clone();
+        let progress_name = options.progress_name.clone();
+        let on_chunk = move |n: u64| {
+            let done = downloaded.fetch_add(n, Ordering::Relaxed) + n;
+            if !progress_name.is_empty() {
+                xu_progress::set(&progress_name, done as f32 / total_size as f32, "");
+            }
+        };
+        tasks.spawn(download_segment(
+            client.clone(),
+            request()?,
+            method.clone(),
+            part_path.clone(),
+            segment,
+            on_chunk,
+        ));
+    }
+
+    // The other segments are aborted when one fails
+    while let Some(res) = tasks.join_next().await {
+        let res = res.unwrap_or_else(|e| Err(io::Error::other(e)));
+        if let Err(e) = res {
+            tasks.abort_all();
+            while tasks.join_next().await.is_some() {}
+            let _ = fs::remove_file(&part_path);
+            return Err(Box::new(e));
+        }
+    }
+
+    if !options.sha256.is_empty() {
+        let sha256 = sha256_by_file_path(&part_path)?;
+        if !sha256.eq_ignore_ascii_case(&options.sha256) {
+            let _ = fs::remove_file(&part_path);
+            return Err(download_err(format!(
+                "sha256 mismatch: expected {}, got {}",
+                options.sha256, sha256
+            )));
+        }
+    }
+
+    fs::rename(&part_path, Path::new(file_path))?;
+    if !options.progress_name.is_empty() {
+        xu_progress::set(&options.progress_name, 1.0, "");
+    }
+
+    Ok(DownloadRes {
+        total_size,
+        resumed_from: 0,
+    })
+}
+
+#[tokio::test]
+async fn test_download_resumable() {
+    use crate::hash::sha256_by_bytes;
+    use crate::web_client::{http_response, serve_http, HttpClientOptions};
+
+    let dir = std::env::temp_dir().join("fivim_rs_utils_test_web_download");
+    let _ = fs::remove_dir_all(&dir);
+    let file_path = x_fs::path_buf_to_string(dir.join("a.txt"));
+    let part_path = format!("{}.part", file_path);
+    let meta_path = format!("{}.part.json", file_path);
+    let empty: HashMap<String, String> = HashMap::new();
+    let client = HttpClient::new(&HttpClientOptions::new()).unwrap();
+
+    // Fresh download
+    let (url, server) =
+        serve_http([http_response("200 OK", &["ETag: \"v1\""], b"hello world")].to_vec());
+    let mut options = DownloadOptions::new();
+    options.sha256 = sha256_by_bytes(b"hello world");
+    let res = download_resumable(
+        &client,
+        HttpMethod::Get,
+        &url,
+        &file_path,
+        &empty,
+        &empty,
+        &options,
+    )
+    .await
+    .unwrap();
+    let _ = server.join();
+    assert_eq!(res.total_size, 11);
+    assert_eq!(fs::read_to_string(&file_path).unwrap(), "hello world");
+    assert!(!Path::new(&part_path).exists() && !Path::new(&meta_path).exists());
+
+    // Interrupted download resumed with a range
+    let (url, server) = serve_http(
+        [http_response(
+            "206 Partial Content",
+            &["Content-Range: bytes 6-10/11"],
+            b"world",
+        )]
+        .to_vec(),
+    );
+    let meta = format!(r#"{{"url":"{}","etag":"\"v1\"","last_modified":""}}"#, url);
+    fs::write(&part_path, "hello ").unwrap();
+    fs::write(&meta_path, &meta).unwrap();
+    let res = download_resumable(
+        &client,
+        HttpMethod::Get,
+        &url,
+        &file_path,
+        &empty,
+        &empty,
+        &options,
+    )
+    .await
+    .unwrap();
+    let request = String::from_utf8(server.join().unwrap().remove(0))
+        .unwrap()
+        .to_lowercase();
+    assert!(request.contains("range: bytes=6-"));
+    assert!(request.contains("if-range: \"v1\""));
+    assert_eq!(res.resumed_from, 6);
+    assert_eq!(fs::read_to_string(&file_path).unwrap(), "hello world");
+
+    // The remote file changed, the server sends it whole
+    let (url, server) =
+        serve_http([http_response("200 OK", &["ETag: \"v2\""], b"HELLO WORLD")].to_vec());
+    let meta = format!(r#"{{"url":"{}","etag":"\"v1\"","last_modified":""}}"#, url);
+    fs::write(&part_path, "hello ").unwrap();
+    fs::write(&meta_path, &meta).unwrap();
+    options.sha256 = "".to_string();
+    let res = download_resumable(
+        &client,
+        HttpMethod::Get,
+        &url,
+        &file_path,
+        &empty,
+        &empty,
+        &options,
+    )
+    .await
+    .unwrap();
+    let _ = server.join();
+    assert_eq!(res.resumed_from, 0);
+    assert_eq!(fs::read_to_string(&file_path).unwrap(), "HELLO WORLD");
+
+    // A part without its metadata is downloaded again from the start
+    let (url, server) =
+        serve_http([http_response("200 OK", &["ETag: \"v1\""], b"hello world")].to_vec());
+    fs::write(&part_path, "stale part").unwrap();
+    let _ = fs::remove_file(&meta_path);
+    let res = download_resumable(
+        &client,
+        HttpMethod::Get,
+        &url,
+        &file_path,
+        &empty,
+        &empty,
+        &options,
+    )
+    .await
+    .unwrap();
+    let request = String::from_utf8(server.join().unwrap().remove(0))
+        .unwrap()
+        .to_lowercase();
+    assert!(!request.contains("range:"));
+    assert_eq!(res.resumed_from, 0);
+    assert_eq!(fs::read_to_string(&file_path).unwrap(), "hello world");
+    assert!(!Path::new(&part_path).exists() && !Path::new(&meta_path).exists());
+
+    // A range not starting where the part ends, and a wrong hash
+    let (url, server) = serve_http(
+        [
+            http_response(
+                "206 Partial Content",
+                &["Content-Range: bytes 5-10/11"],
+                b" world",
+            ),
+            http_response("200 OK", &[], b"hello world"),
+        ]
+        .to_vec(),
+    );
+    let meta = format!(r#"{{"url":"{}","etag":"\"v1\"","last_modified":""}}"#, url);
+    fs::write(&part_path, "hello ").unwrap();
+    fs::write(&meta_path, &meta).unwrap();
+    let download = |sha256: &str| {
+        let mut options = DownloadOptions::new();
+        options.sha256 = sha256.to_string();
+        let (client, url, file_path, empty) = (&client, &url, &file_path, &empty);
+        async move {
+            download_resumable(
+                client,
+                HttpMethod::Get,
+                url,
+                file_path,
+                empty,
+                empty,
+                &options,
+            )
+            .await
+        }
+    };
+    assert!(download("").await.is_err());
+    assert!(!Path::new(&part_path).exists());
+    assert!(download("00").await.is_err());
+    assert!(!Path::new(&part_path).exists());
+    let _ = server.join();
+
+    assert_eq!(parse_content_range("bytes 0-99/*"), Some((0, 99, None)));
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn test_download_segmented() {
+    use crate::hash::sha256_by_bytes;
+    use crate::web_client::{http_response, serve_http, serve_http_with, HttpClientOptions};
+
+    let dir = std::env::temp_dir().join("fivim_rs_utils_test_web_download_segmented");
+    let _ = fs::remove_dir_all(&dir);
+    let file_path = x_fs::path_buf_to_string(dir.join("a.bin"));
+    let empty: HashMap<String, String> = HashMap::new();
+    let client = HttpClient::new(&HttpClientOptions::new()).unwrap();
+    let body: Vec<u8> = (0..1000u32).map(|i| (i * 7 % 251) as u8).collect();
+
+    // Answer each range with its bytes
+    let serve = |count: usize| {
+        let body = body.clone();
+        serve_http_with(count, move |req| {
+            let req = String::from_utf8_lossy(req).to_lowercase();
+            let range = req
+                .lines()
+                .find_map(|l| l.strip_prefix("range: bytes="))
+                .unwrap();
+            let (start, end) = range.trim().split_once('-').unwrap();
+            let (start, end): (usize, usize) = (start.parse().unwrap(), end.parse().unwrap());
+            http_response(
+                "206 Partial Content",
+                &[
+                    "ETag: \"v1\"",
+                    &format!("Content-Range: bytes {}-{}/{}", start, end, body.len()),
+                ],
+                &body[start..=end],
+            )
+        })
+    };
+
+    let mut options = DownloadOptions::new();
+    options.min_segment_size = 100;
+    options.progress_name = "test_download_segmented".to_string();
+    options.sha256 = sha256_by_bytes(&body);
+    let (url, server) = serve(1 + 4);
+    let res = download_segmented(
+        &client,
+        HttpMethod::Get,
+        &url,
+        &file_path,
+        &empty,
+        &empty,
+        &options,
+    )
+    .await
+    .unwrap();
+    let requests = server.join().unwrap();
+    assert_eq!(res.total_size, 1000);
+    assert_eq!(fs::read(&file_path).unwrap(), body);
+    assert!(String::from_utf8_lossy(&requests[1]).contains("if-range: \"v1\""));
+    let mut ranges: Vec<String> = requests[1..]
+        .iter()
+        .map(|r| {
+            let r = String::from_utf8_lossy(r).to_lowercase();
+            r.lines()
+                .find_map(|l| l.strip_prefix("range: bytes="))
+                .unwrap()
+                .to_string()
+        })
+        .collect();
+    ranges.sort();
+    assert_eq!(ranges, ["0-249", "250-499", "500-749", "750-999"]);
+    let status = serde_json::to_value(xu_progress::get("test_download_segmented")).unwrap();
+    assert_eq!(status["percentage"], 1.0);
+
+    // Smaller files use fewer segments
+    options.min_segment_size = 400;
+    let (url, server) = serve(1 + 2);
+    download_segmented(
+        &client,
+        HttpMethod::Get,
+        &url,
+        &file_path,
+        &empty,
+        &empty,
+        &options,
+    )
+    .await
+    .unwrap();
+    assert_eq!(server.join().unwrap().len(), 3);
+    assert_eq!(fs::read(&file_path).unwrap(), body);
+
+    // Without range support the answer to the probe is the whole file, it is not requested again
+    let (url, server) = serve_http([http_response("200 OK", &[], &body)].to_vec());
+    let _ = fs::remove_file(&file_path);
+    let res = download_segmented(
+        &client,
+        HttpMethod::Get,
+        &url,
+        &file_path,
+        &empty,
+        &empty,
+        &options,
+    )
+    .await
+    .unwrap();
+    assert_eq!(server.join().unwrap().len(), 1);
+    assert_eq!(res.total_size, 1000);
+    assert_eq!(fs::read(&file_path).unwrap(), body);
+
+    let _ = fs::remove_dir_all(&dir);
+}
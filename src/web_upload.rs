@@ -0,0 +1,430 @@
+use futures_util::stream::{self, Stream};
+use reqwest::header;
+use reqwest::multipart::{Form, Part};
+use reqwest::Body;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::mem;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+use crate::progress as xu_progress;
+use crate::web::{build_request, read_response, HttpMethod, HttpResponse, ReaponseDataType};
+use crate::web_client::{send_with_retry, HttpClient};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+const MIME_OCTET_STREAM: &str = "application/octet-stream";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum FormValue {
+    Text(String),
+    Bytes(Vec<u8>),
+    // Path of a file streamed from disk
+    File(String),
+}
+
+// A field of a multipart/form-data body
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FormPart {
+    pub name: String,
+    pub value: FormValue,
+    // Sent file name of a bytes or file part, the name of the file by default
+    pub file_name: String,
+    // Content type of a bytes or file part, application/octet-stream if empty
+    pub mime: String,
+}
+
+impl FormPart {
+    pub fn text(name: &str, value: &str) -> FormPart {
+        FormPart {
+            name: name.to_string(),
+            value: FormValue::Text(value.to_string()),
+            file_name: "".to_string(),
+            mime: "".to_string(),
+        }
+    }
+
+    pub fn bytes(name: &str, data: Vec<u8>, file_name: &str) -> FormPart {
+        FormPart {
+            name: name.to_string(),
+            value: FormValue::Bytes(data),
+            file_name: file_name.to_string(),
+            mime: "".to_string(),
+        }
+    }
+
+    pub fn file(name: &str, file_path: &str) -> FormPart {
+        let file_name = Path::new(file_path)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        FormPart {
+            name: name.to_string(),
+            value: FormValue::File(file_path.to_string()),
+            file_name,
+            mime: "".to_string(),
+        }
+    }
+
+    pub fn with_mime(mut self, mime: &str) -> FormPart {
+        self.mime = mime.to_string();
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum RequestBody {
+    Empty,
+    Text(String),
+    Bytes(Vec<u8>),
+    // Path of a file streamed from disk
+    File(String),
+    Multipart(Vec<FormPart>),
+}
+
+// Bytes sent of all the bytes and file payloads of a body
+struct UploadProgress {
+    name: String,
+    sent: AtomicU64,
+    // Set once all the payloads are opened, before anything is sent
+    total: AtomicU64,
+}
+
+impl UploadProgress {
+    fn add(&self, n: usize) {
+        let sent = self.sent.fetch_add(n as u64, Ordering::Relaxed) + n as u64;
+        let total = self.total.load(Ordering::Relaxed);
+        if !self.name.is_empty() && total > 0 {
+            let pct = sent as f32 / total as f32;
+            xu_progress::set(&self.name, pct.min(1.0), "");
+        }
+    }
+}
+
+enum Source {
+    Bytes(Vec<u8>, usize),
+    File(File),
+}
+
+impl Source {
+    async fn open(value: FormValue) -> Result<(Source, u64), io::Error> {
+        match value {
+            FormValue::File(file_path) => {
+                let file = File::open(file_path).await?;
+                let len = file.metadata().await?.len();
+                Ok((Source::File(file), len))
+            }
+            FormValue::Bytes(data) => {
+                let len = data.len() as u64;
+                Ok((Source::Bytes(data, 0), len))
+            }
+            FormValue::Text(text) => {
+                let len = text.len() as u64;
+                Ok((Source::Bytes(text.into_bytes(), 0), len))
+            }
+        }
+    }
+
+    // An empty chunk at the end
+    async fn next_chunk(&mut self) -> Result<Vec<u8>, io::Error> {
+        match self {
+            Source::Bytes(data, offset) => {
+                let end = (*offset + CHUNK_SIZE).min(data.len());
+                let chunk = data[*offset..end].to_vec();
+                *offset = end;
+                Ok(chunk)
+            }
+            Source::File(file) => {
+                let mut chunk = vec![0; CHUNK_SIZE];
+                let n = file.read(&mut chunk).await?;
+                chunk.truncate(n);
+                Ok(chunk)
+            }
+        }
+    }
+}
+
+// The chunks of a source, counted in the progress as they are sent
+fn counted_stream(
+    source: Source,
+    progress: Arc<UploadProgress>,
+) -> impl Stream<Item = Result<Vec<u8>, io::Error>> + Send + 'static {
+    stream::unfold(Some(source), move |source| {
+        let progress = progress.clone();
+        async move {
+            let mut source = source?;
+            match source.next_chunk().await {
+                Ok(chunk) if chunk.is_empty() => None,
+                Ok(chunk) => {
+                    progress.add(chunk.len());
+                    Some((Ok(chunk), Some(source)))
+                }
+                // Nothing more after an error
+                Err(e) => Some((Err(e), None)),
+            }
+        }
+    })
+}
+
+async fn build_form(
+    parts: Vec<FormPart>,
+    progress: &Arc<UploadProgress>,
+) -> Result<Form, Box<dyn Error>> {
+    // The payloads are all opened first, for the total of the progress
+    let mut opened: Vec<(FormPart, Option<(Source, u64)>)> = Vec::new();
+    for mut part in parts {
+        let source = match part.value {
+            FormValue::Text(_) => None,
+            _ => {
+                let value = mem::replace(&mut part.value, FormValue::Bytes(Vec::new()));
+                Some(Source::open(value).await?)
+            }
+        };
+        opened.push((part, source));
+    }
+    let total = opened
+        .iter()
+        .filter_map(|(_, s)| s.as_ref().map(|s| s.1))
+        .sum();
+    progress.total.store(total, Ordering::Relaxed);
+
+    let mut form = Form::new();
+    for (part, source) in opened {
+        let (source, len) = match source {
+            Some(s) => s,
+            // A text field
+            None => {
+                if let FormValue::Text(text) = part.value {
+                    let mut p = Part::text(text);
+                    if !part.mime.is_empty() {
+                        p = p.mime_str(&part.mime)?;
+                    }
+                    form = form.part(part.name, p);
+                }
+                continue;
+            }
+        };
+        let body = Part::stream_with_length(
+            Body::wrap_stream(counted_stream(source, progress.clone())),
+            len,
+        );
+
+        let mime = if part.mime.is_empty() {
+            MIME_OCTET_STREAM
+        } else {
+            &part.mime
+        };
+        form = form.part(part.name, body.file_name(part.file_name).mime_str(mime)?);
+    }
+    Ok(form)
+}
+
+pub async fn upload(
+    method: HttpMethod,
+    url: &str,
+    headers_map: &HashMap<String, String>,
+    params_map: &HashMap<String, String>,
+    body: RequestBody,
+    progress_name: &str,
+    resp_data_type: ReaponseDataType,
+) -> Result<HttpResponse, Box<dyn Error>> {
+    upload_with_client(
+        &HttpClient::shared()?,
+        method,
+        url,
+        headers_map,
+        params_map,
+        body,
+        progress_name,
+        resp_data_type,
+    )
+    .await
+}
+
+// Send a request with a body of any kind. Files and multipart bodies are streamed in chunks,
+// files from disk, and counted in the progress `progress_name` (none if empty) as they are
+// sent. Streamed bodies are sent once whatever the retry policy of the client, text and bytes
+// bodies are kept in memory and can be retried, they are counted once the request is sent.
+#[allow(clippy::too_many_arguments)]
+pub async fn upload_with_client(
+    client: &HttpClient,
+    method: HttpMethod,
+    url: &str,
+    headers_map: &HashMap<String, String>,
+    params_map: &HashMap<String, String>,
+    body: RequestBody,
+    progress_name: &str,
+    resp_data_type: ReaponseDataType,
+) -> Result<HttpResponse, Box<dyn Error>> {
+    let progress = Arc::new(UploadProgress {
+        name: progress_name.to_string(),
+        sent: AtomicU64::new(0),
+        total: AtomicU64::new(0),
+    });
+    if !progress_name.is_empty() {
+        xu_progress::insert_new(progress_name);
+    }
+
+    let in_memory = match &body {
+        RequestBody::Text(text) => text.len(),
+        RequestBody::Bytes(data) => data.len(),
+        _ => 0,
+    };
+    progress.total.store(in_memory as u64, Ordering::Relaxed);
+
+    let mut request = build_request(
+        client.inner(),
+        url,
+        &method,
+        headers_map.clone(),
+        params_map.clone(),
+    )?;
+    request = match body {
+        RequestBody::Empty => request,
+        RequestBody::Text(text) => request.body(text),
+        RequestBody::Bytes(data) => request.body(data),
+        RequestBody::File(file_path) => {
+            let (source, len) = Source::open(FormValue::File(file_path)).await?;
+            progress.total.store(len, Ordering::Relaxed);
+            request
+                .header(header::CONTENT_LENGTH, len)
+                .body(Body::wrap_stream(counted_stream(source, progress.clone())))
+        }
+        RequestBody::Multipart(parts) => request.multipart(build_form(parts, &progress).await?),
+    };
+
+    let ret = send_with_retry(client, &method, request).await?;
+    if in_memory > 0 {
+        progress.add(in_memory);
+    }
+    let res = read_response(ret, resp_data_type).await?;
+    if !progress_name.is_empty() {
+        xu_progress::set(progress_name, 1.0, "");
+    }
+    Ok(res)
+}
+
+#[tokio::test]
+async fn test_upload() {
+    use crate::web_client::{http_response, serve_http, HttpClientOptions};
+
+    let dir = std::env::temp_dir().join("fivim_rs_utils_test_web_upload");
+    let _ = std::fs::remove_dir_all(&dir);
+    let _ = std::fs::create_dir_all(&dir);
+    let file_path = crate::fs::path_buf_to_string(dir.join("a.bin"));
+    let content: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&file_path, &content).unwrap();
+
+    let client = HttpClient::new(&HttpClientOptions::new()).unwrap();
+    let empty: HashMap<String, String> = HashMap::new();
+    let upload = |body: RequestBody| {
+        let (client, empty) = (&client, &empty);
+        async move {
+            let (url, server) = serve_http([http_response("200 OK", &[], b"done")].to_vec());
+            let res = upload_with_client(
+                client,
+                HttpMethod::Put,
+                &url,
+                empty,
+                empty,
+                body,
+                "test_upload",
+                ReaponseDataType::Text,
+            )
+            .await
+            .unwrap();
+            assert_eq!(res.text, "done");
+            server.join().unwrap().remove(0)
+        }
+    };
+    let ends_with = |req: &[u8], body: &[u8]| req.ends_with(body);
+
+    // A file streamed from disk, with its length
+    let req = upload(RequestBody::File(file_path.clone())).await;
+    let head = String::from_utf8_lossy(&req).to_lowercase();
+    assert!(head.contains("content-length: 200000"));
+    assert!(ends_with(&req, &content));
+    let status = serde_json::to_value(xu_progress::get("test_upload")).unwrap();
+    assert_eq!(status["percentage"], 1.0);
+
+    let req = upload(RequestBody::Bytes(b"\x00\x01binary".to_vec())).await;
+    assert!(ends_with(&req, b"\x00\x01binary"));
+
+    // Bytes are kept in memory, so a PUT is sent again after a 503
+    let mut options = HttpClientOptions::new();
+    options.retry.initial_backoff_ms = 1;
+    let (url, server) = serve_http(
+        [
+            http_response("503 Service Unavailable", &[], b""),
+            http_response("200 OK", &[], b"done"),
+        ]
+        .to_vec(),
+    );
+    let res = upload_with_client(
+        &HttpClient::new(&options).unwrap(),
+        HttpMethod::Put,
+        &url,
+        &empty,
+        &empty,
+        RequestBody::Bytes(b"retried".to_vec()),
+        "",
+        ReaponseDataType::Text,
+    )
+    .await
+    .unwrap();
+    assert_eq!(res.status, 200);
+    let reqs = server.join().unwrap();
+    assert!(reqs.iter().all(|r| r.ends_with(b"retried")));
+
+    // Fields and files mixed
+    let req = upload(RequestBody::Multipart(
+        [
+            FormPart::text("title", "backup"),
+            FormPart::file("archive", &file_path).with_mime("application/zip"),
+            FormPart::bytes("thumb", b"PNG".to_vec(), "thumb.png").with_mime("image/png"),
+        ]
+        .to_vec(),
+    ))
+    .await;
+    let progress = Arc::new(UploadProgress {
+        name: "".to_string(),
+        sent: AtomicU64::new(0),
+        total: AtomicU64::new(0),
+    });
+    let parts = [
+        FormPart::text("title", "backup"),
+        FormPart::file("archive", &file_path),
+        FormPart::bytes("thumb", b"PNG".to_vec(), "thumb.png"),
+    ];
+    build_form(parts.to_vec(), &progress).await.unwrap();
+    assert_eq!(progress.total.load(Ordering::Relaxed), 200_003);
+    assert_eq!(progress.sent.load(Ordering::Relaxed), 0);
+    let text = String::from_utf8_lossy(&req);
+    assert!(text
+        .to_lowercase()
+        .contains("content-type: multipart/form-data; boundary="));
+    assert!(text.contains("name=\"title\"\r\n\r\nbackup\r\n"));
+    assert!(text.contains("name=\"archive\"; filename=\"a.bin\"\r\nContent-Type: application/zip"));
+    assert!(text.contains("filename=\"thumb.png\"\r\nContent-Type: image/png\r\n\r\nPNG\r\n"));
+    assert!(req.windows(content.len()).any(|w| w == content.as_slice()));
+
+    let res = upload_with_client(
+        &client,
+        HttpMethod::Put,
+        "http://127.0.0.1:1",
+        &empty,
+        &empty,
+        RequestBody::File(crate::fs::path_buf_to_string(dir.join("missing"))),
+        "",
+        ReaponseDataType::Text,
+    )
+    .await;
+    assert!(res.is_err());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}